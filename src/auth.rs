@@ -0,0 +1,95 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{Context, Result};
+use reqwest::{
+    Url,
+    header::{HeaderValue, InvalidHeaderValue},
+};
+use serde::Deserialize;
+
+/// The credentials files of `docker login` and `podman login`, which share a format.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AuthFile {
+    auths: HashMap<String, AuthEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct AuthEntry {
+    /// `user:password`, base64-encoded: exactly what HTTP basic auth wants
+    auth: Option<String>,
+    /// A bearer token for the registry
+    registrytoken: Option<String>,
+}
+
+impl AuthEntry {
+    fn header(&self) -> Option<Result<HeaderValue, InvalidHeaderValue>> {
+        let value = match (&self.registrytoken, &self.auth) {
+            (Some(token), _) => format!("Bearer {token}"),
+            (None, Some(auth)) => format!("Basic {auth}"),
+            (None, None) => return None,
+        };
+        Some(HeaderValue::from_str(&value).map(|mut header| {
+            header.set_sensitive(true);
+            header
+        }))
+    }
+}
+
+/// The credentials files we look at, in order: the ones of podman and skopeo, and then the one of
+/// docker.  containers/image (which does our pulls) looks at the same ones.
+fn auth_files() -> Vec<PathBuf> {
+    let mut files = vec![];
+    if let Some(path) = std::env::var_os("REGISTRY_AUTH_FILE") {
+        files.push(PathBuf::from(path));
+    }
+    if let Some(dir) = dirs::runtime_dir() {
+        files.push(dir.join("containers/auth.json"));
+    }
+    if let Some(dir) = dirs::config_dir() {
+        files.push(dir.join("containers/auth.json"));
+    }
+    if let Some(dir) = dirs::home_dir() {
+        files.push(dir.join(".docker/config.json"));
+    }
+    files
+}
+
+/// Reduces a key of the `auths` table, like `https://registry.example.com/v1/`, to its host.
+fn key_host(key: &str) -> &str {
+    let key = key.split_once("://").map_or(key, |(_, rest)| rest);
+    key.split('/').next().unwrap_or(key)
+}
+
+/// Finds the Authorization header for the registry at `url` in the credentials files, or None
+/// to access it anonymously.
+pub(crate) fn registry_authorization(url: &Url) -> Result<Option<HeaderValue>> {
+    let Some(host) = url.host_str() else {
+        return Ok(None);
+    };
+    let host = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+
+    for path in auth_files() {
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => Err(err).with_context(|| format!("Unable to read {path:?}"))?,
+        };
+        let file: AuthFile = serde_json::from_slice(&content)
+            .with_context(|| format!("Invalid credentials file {path:?}"))?;
+
+        let entry = file.auths.iter().find(|(key, _)| key_host(key) == host);
+        if let Some(header) = entry.and_then(|(_, entry)| entry.header()) {
+            log::debug!("Using credentials for {host} from {path:?}");
+            return Ok(Some(header.with_context(|| {
+                format!("Invalid credentials for {host} in {path:?}")
+            })?));
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,347 @@
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use composefs::{
+    fsverity::FsVerityHashValue,
+    repository::Repository,
+    tree::{Directory, FileSystem, Inode, LeafContent, RegularFile},
+};
+
+use crate::r#ref::Ref;
+
+/// Finds a directory in the image by its path, or None if it's not there.
+fn find_directory<'a, ObjectID: FsVerityHashValue>(
+    dir: &'a Directory<ObjectID>,
+    path: &str,
+) -> Option<&'a Directory<ObjectID>> {
+    path.split('/')
+        .try_fold(dir, |dir, name| dir.get_directory(name.as_ref()).ok())
+}
+
+fn read_file<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    file: &RegularFile<ObjectID>,
+) -> Result<Vec<u8>> {
+    match file {
+        RegularFile::Inline(data) => Ok(data.clone().into_vec()),
+        RegularFile::External(id, ..) => {
+            let mut data = vec![];
+            File::from(repo.open_object(id)?).read_to_end(&mut data)?;
+            Ok(data)
+        }
+    }
+}
+
+/// Like flatpak, we only export files named after the app, like `org.gnome.Calculator.desktop`
+/// or `org.gnome.Calculator.SearchProvider.svg`, so apps can't replace the files of others.
+fn is_exportable(name: &str, id: &str) -> bool {
+    name.strip_prefix(id)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Splits the value of an Exec= key into the command and its arguments, following the desktop
+/// entry spec: the escapes of strings (like `\s`) are undone first, and then the arguments are
+/// separated by spaces, and can be quoted with double quotes, inside of which a backslash escapes
+/// the next character.
+fn split_exec(exec: &str) -> Vec<String> {
+    let mut unescaped = String::new();
+    let mut chars = exec.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some(next @ ('s' | 'n' | 't' | 'r' | '\\'))) => {
+                chars.next();
+                unescaped.push(match next {
+                    's' => ' ',
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    _ => '\\',
+                });
+            }
+            _ => unescaped.push(c),
+        }
+    }
+
+    let mut args = vec![];
+    let mut current: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = unescaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.get_or_insert_default();
+            }
+            '\\' if quoted => current.get_or_insert_default().extend(chars.next()),
+            ' ' | '\t' | '\n' if !quoted => args.extend(current.take()),
+            c => current.get_or_insert_default().push(c),
+        }
+    }
+    args.extend(current);
+    args
+}
+
+/// The reverse of split_exec(), for a single argument: quotes it if needed and then escapes it
+/// as a string.  Field codes like `%f` don't need quoting, so they keep working.
+fn quote_exec_arg(arg: &str) -> String {
+    const RESERVED: &[char] = &[
+        ' ', '\t', '\n', '"', '\'', '\\', '>', '<', '~', '|', '&', ';', '$', '*', '?', '#', '(',
+        ')', '`',
+    ];
+
+    let mut quoted = String::new();
+    if arg.is_empty() || arg.contains(RESERVED) {
+        quoted.push('"');
+        for c in arg.chars() {
+            if matches!(c, '"' | '`' | '$' | '\\') {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.push('"');
+    } else {
+        quoted.push_str(arg);
+    }
+
+    let mut escaped = String::new();
+    for c in quoted.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Rewrites the Exec= lines of a desktop file to run the command in the sandbox.  TryExec= is
+/// dropped: the binary it refers to only exists in the sandbox.
+fn rewrite_desktop_file(contents: &str, launcher: &str, r#ref: &Ref) -> String {
+    let mut result = String::new();
+    for line in contents.lines() {
+        if line.starts_with("TryExec=") {
+            continue;
+        }
+        if let Some(exec) = line.strip_prefix("Exec=") {
+            let args = split_exec(exec);
+            let Some((command, args)) = args.split_first() else {
+                continue;
+            };
+            let mut exec = vec![
+                launcher.to_string(),
+                "run".to_string(),
+                format!("--command={command}"),
+                r#ref.to_string(),
+            ];
+            if !args.is_empty() {
+                exec.push("--".to_string());
+                exec.extend_from_slice(args);
+            }
+            let exec: Vec<_> = exec.iter().map(|arg| quote_exec_arg(arg)).collect();
+            result.push_str(&format!("Exec={}", exec.join(" ")));
+        } else {
+            result.push_str(line);
+        }
+        result.push('\n');
+    }
+    result
+}
+
+/// Copies the icons below a directory in the image to the same place below `target`.
+fn export_icons<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    dir: &Directory<ObjectID>,
+    target: &Path,
+    id: &str,
+) -> Result<()> {
+    for (name, inode) in dir.entries() {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        match inode {
+            Inode::Directory(subdir) => export_icons(repo, subdir, &target.join(name), id)?,
+            Inode::Leaf(leaf) => {
+                let LeafContent::Regular(file) = &leaf.content else {
+                    continue;
+                };
+                if !is_exportable(name, id) {
+                    continue;
+                }
+                fs::create_dir_all(target)
+                    .with_context(|| format!("Unable to create {target:?}"))?;
+                let path = target.join(name);
+                fs::write(&path, read_file(repo, file)?)
+                    .with_context(|| format!("Unable to write {path:?}"))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a file that we exported, if it's still there.
+fn remove_export(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            Err(err).with_context(|| format!("Unable to remove {path:?}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Removes the icons that export_icons() copied from `dir` to below `target`.
+fn remove_icons<ObjectID: FsVerityHashValue>(
+    dir: &Directory<ObjectID>,
+    target: &Path,
+    id: &str,
+) -> Result<()> {
+    for (name, inode) in dir.entries() {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        match inode {
+            Inode::Directory(subdir) => remove_icons(subdir, &target.join(name), id)?,
+            Inode::Leaf(leaf) => {
+                if matches!(leaf.content, LeafContent::Regular(..)) && is_exportable(name, id) {
+                    remove_export(&target.join(name))?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Exports the desktop files and icons of an app from `/app/share` to the user's data directory
+/// on the host, so that the app shows up in the menus of the desktop.
+pub(crate) fn export_desktop_files<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+    filesystem: &FileSystem<ObjectID>,
+) -> Result<()> {
+    let Some(share) = find_directory(&filesystem.root, "files/share") else {
+        return Ok(());
+    };
+    let data_dir = dirs::data_dir().context("Unable to find the data directory")?;
+    let id = r#ref.get_id();
+
+    if let Some(applications) = find_directory(share, "applications") {
+        let launcher = std::env::current_exe().context("Unable to find our own executable")?;
+        let launcher = launcher.to_str().context("Our path is not valid utf-8")?;
+        let target = data_dir.join("applications");
+
+        for (name, inode) in applications.entries() {
+            let Some(name) = name.to_str().filter(|name| name.ends_with(".desktop")) else {
+                continue;
+            };
+            let Inode::Leaf(leaf) = inode else {
+                continue;
+            };
+            let LeafContent::Regular(file) = &leaf.content else {
+                continue;
+            };
+            if !is_exportable(name, id) {
+                log::debug!("Not exporting {name}: it's not named after {id}");
+                continue;
+            }
+
+            let contents = String::from_utf8(read_file(repo, file)?)
+                .with_context(|| format!("{name} is not valid utf-8"))?;
+            fs::create_dir_all(&target).with_context(|| format!("Unable to create {target:?}"))?;
+            let path = target.join(name);
+            fs::write(&path, rewrite_desktop_file(&contents, launcher, r#ref))
+                .with_context(|| format!("Unable to write {path:?}"))?;
+        }
+    }
+
+    if let Some(icons) = find_directory(share, "icons") {
+        export_icons(repo, icons, &data_dir.join("icons"), id)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the desktop files and icons that export_desktop_files() exported for the app whose
+/// image is `filesystem`.  Going by the image (rather than the names in the data directory) means
+/// that we don't touch the files of other apps whose IDs start with the same name.
+pub(crate) fn remove_desktop_files<ObjectID: FsVerityHashValue>(
+    r#ref: &Ref,
+    filesystem: &FileSystem<ObjectID>,
+) -> Result<()> {
+    let Some(share) = find_directory(&filesystem.root, "files/share") else {
+        return Ok(());
+    };
+    let data_dir = dirs::data_dir().context("Unable to find the data directory")?;
+    let id = r#ref.get_id();
+
+    if let Some(applications) = find_directory(share, "applications") {
+        let target = data_dir.join("applications");
+        for (name, inode) in applications.entries() {
+            let Some(name) = name.to_str().filter(|name| name.ends_with(".desktop")) else {
+                continue;
+            };
+            if matches!(inode, Inode::Leaf(..)) && is_exportable(name, id) {
+                remove_export(&target.join(name))?;
+            }
+        }
+    }
+
+    if let Some(icons) = find_directory(share, "icons") {
+        remove_icons(icons, &data_dir.join("icons"), id)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exec_splitting() {
+        assert_eq!(split_exec("gimp %U"), ["gimp", "%U"]);
+        assert_eq!(
+            split_exec(r#""/app/bin/my app"  --name="a \"b\"" %f"#),
+            ["/app/bin/my app", "--name=a \"b\"", "%f"]
+        );
+        // The string escapes come first, so a quoted backslash takes four of them
+        assert_eq!(
+            split_exec(r#"echo "a\\\\b" "c\sd""#),
+            ["echo", r"a\b", "c d"]
+        );
+        assert_eq!(split_exec(r#"run """#), ["run", ""]);
+        assert!(split_exec("  ").is_empty());
+    }
+
+    #[test]
+    fn exec_quoting_round_trips() {
+        for arg in [
+            "plain",
+            "%u",
+            "two words",
+            r#"a "quoted" $var"#,
+            r"back\slash",
+            "",
+        ] {
+            assert_eq!(split_exec(&quote_exec_arg(arg)), [arg]);
+        }
+    }
+
+    #[test]
+    fn desktop_file_rewriting() {
+        let r#ref: Ref = "app/org.example.App/x86_64/stable".parse().unwrap();
+        let contents =
+            "[Desktop Entry]\nTryExec=example\nExec=\"/app/bin/an example\" --new-window %U\n";
+        assert_eq!(
+            rewrite_desktop_file(contents, "/usr/bin/flatpak-next", &r#ref),
+            "[Desktop Entry]\nExec=/usr/bin/flatpak-next run \"--command=/app/bin/an example\" \
+             app/org.example.App/x86_64/stable -- --new-window %U\n"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{Context, Result, bail};
+use ini::Ini;
+
+use crate::{Args, Cmd, r#ref::Ref};
+
+/// A `.flatpakref` file, as found on websites for installing an app with a click.  Only the ones
+/// pointing at OCI registries (with a `Url=oci+https://...`) are any use to us.
+#[derive(Debug)]
+pub(crate) struct FlatpakRef {
+    /// The registry, like `https://registry.fedoraproject.org/`
+    pub(crate) repository: String,
+    pub(crate) r#ref: Ref,
+    pub(crate) title: Option<String>,
+}
+
+impl FlatpakRef {
+    /// Parses the file.  It doesn't name the architecture, so that has to come from us.
+    pub(crate) fn new(s: &str, arch: &str) -> Result<Self> {
+        let ini = Ini::load_from_str(s).context("Failed to parse flatpakref")?;
+        let section = ini
+            .section(Some("Flatpak Ref"))
+            .context("flatpakref has no [Flatpak Ref] section")?;
+        let get = |key| {
+            section
+                .get(key)
+                .with_context(|| format!("Section [Flatpak Ref] is missing {key}="))
+        };
+
+        let url = get("Url")?;
+        let Some(repository) = url.strip_prefix("oci+") else {
+            bail!(
+                "{url} is an OSTree repository: only OCI registries (oci+https://) are supported"
+            );
+        };
+        // The index gets joined onto it, which would replace a last part without the slash
+        let mut repository = repository.to_string();
+        if !repository.ends_with('/') {
+            repository.push('/');
+        }
+
+        let kind = match section.get("IsRuntime") {
+            Some("true") => "runtime",
+            _ => "app",
+        };
+        let name = get("Name")?;
+        let branch = section.get("Branch").unwrap_or("master");
+        let r#ref = format!("{kind}/{name}/{arch}/{branch}")
+            .try_into()
+            .context("Invalid Name= or Branch= in flatpakref")?;
+
+        Ok(Self {
+            repository,
+            r#ref,
+            title: section.get("Title").map(str::to_string),
+        })
+    }
+
+    /// Reads the file from a local path or an `http(s)://` URL.
+    pub(crate) async fn load(location: &str, arch: &str) -> Result<Self> {
+        let content = if location.starts_with("https://") || location.starts_with("http://") {
+            reqwest::get(location)
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .with_context(|| format!("Unable to download {location}"))?
+                .text()
+                .await
+                .with_context(|| format!("Unable to download {location}"))?
+        } else {
+            std::fs::read_to_string(location)
+                .with_context(|| format!("Unable to read {location}"))?
+        };
+
+        Self::new(&content, arch).with_context(|| format!("Invalid flatpakref {location}"))
+    }
+}
+
+/// For `install --from`, reads the flatpakref and installs what it points at: this sets the ref
+/// to install and the repository to get it from.
+pub(crate) async fn apply(args: &mut Args) -> Result<()> {
+    let Cmd::Install {
+        r#ref,
+        from: Some(location),
+        ..
+    } = &mut args.command
+    else {
+        return Ok(());
+    };
+
+    let flatpakref = FlatpakRef::load(location, &args.arch).await?;
+    if let Some(title) = &flatpakref.title {
+        println!("Installing {title} from {location}");
+    }
+    *r#ref = Some(flatpakref.r#ref);
+    args.repository = flatpakref.repository;
+
+    Ok(())
+}
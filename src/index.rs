@@ -1,17 +1,50 @@
-use std::{collections::HashMap, fs::create_dir_all, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use dirs::cache_dir;
+use futures::{StreamExt, TryStreamExt, stream};
 use http_cache_reqwest::{CACacheManager, Cache, CacheMode, HttpCache, HttpCacheOptions};
-use reqwest::{Client, Url};
+use reqwest::{
+    Certificate, Client, Response, StatusCode, Url,
+    header::{AUTHORIZATION, DATE, HeaderMap},
+};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize, de::DeserializeOwned};
 
-use crate::r#ref::Ref;
+use crate::{auth::registry_authorization, r#ref::Ref};
+
+/// How many per-name image lists to fetch at the same time
+const CONCURRENT_FETCHES: usize = 8;
+
+/// Deserializes a list, skipping (and logging) the items that don't parse.  One broken entry in
+/// the index of a big registry shouldn't make all of the others unavailable.
+fn skip_invalid<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let values = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(values
+        .into_iter()
+        .filter_map(|value| match serde_json::from_value(value) {
+            Ok(item) => Some(item),
+            Err(err) => {
+                log::warn!("Skipping invalid entry in the index: {err}");
+                None
+            }
+        })
+        .collect())
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct IndexResponse {
+    #[serde(deserialize_with = "skip_invalid")]
     results: Vec<Name>,
 }
 
@@ -19,6 +52,8 @@ struct IndexResponse {
 #[serde(rename_all = "PascalCase")]
 struct Name {
     name: String,
+    // Some registries don't inline the images in the main index
+    #[serde(default, deserialize_with = "skip_invalid")]
     images: Vec<Image>,
 }
 
@@ -26,23 +61,185 @@ struct Name {
 #[serde(rename_all = "PascalCase")]
 struct Image {
     digest: String,
-    labels: Labels,
+    // Fedora has the flatpak metadata in the labels of the image config, but registries like
+    // Flathub's only have it in the annotations of the manifest
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Labels {
-    #[serde(rename = "org.flatpak.ref")]
-    r#ref: Ref,
-    #[serde(rename = "org.flatpak.metadata")]
-    metadata: String,
+impl Image {
+    /// The first of the given keys that's in the labels, or else in the annotations.
+    fn label(&self, keys: &[&str]) -> Option<&str> {
+        [&self.labels, &self.annotations]
+            .into_iter()
+            .find_map(|map| keys.iter().find_map(|key| map.get(*key)))
+            .map(String::as_str)
+    }
+}
+
+/// An image available from the index.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct IndexEntry {
+    /// The image reference, relative to the repository: `name@digest`
+    pub(crate) image: String,
+    /// The digest of the image manifest, like `sha256:...`
+    pub(crate) digest: String,
+    /// The flatpak metadata (manifest) of the image
+    pub(crate) metadata: String,
+    pub(crate) download_size: Option<u64>,
+    pub(crate) installed_size: Option<u64>,
+    /// The human-readable name of the app
+    pub(crate) name: Option<String>,
+    /// A one-line description of the app
+    pub(crate) summary: Option<String>,
 }
 
-fn get_oci_arch() -> &'static str {
-    match std::env::consts::ARCH {
+impl IndexEntry {
+    /// Checks if the (lowercase) search term appears in the name or summary.
+    pub(crate) fn matches(&self, term: &str) -> bool {
+        [&self.name, &self.summary]
+            .into_iter()
+            .flatten()
+            .any(|text| text.to_lowercase().contains(term))
+    }
+
+    /// The best fuzzy_score() of the (lowercase) search term against the ref and the name.
+    pub(crate) fn fuzzy_score(&self, r#ref: &Ref, term: &str) -> Option<u32> {
+        [Some(r#ref.as_ref()), self.name.as_deref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|text| fuzzy_score(term, text))
+            .max()
+    }
+}
+
+/// Scores how well the (lowercase) search term matches `text` as a subsequence, so that
+/// "gnmcalc" matches "org.gnome.Calculator", or returns None if it doesn't match at all.  Runs of
+/// consecutive characters and matches at the start of a word score higher.
+pub(crate) fn fuzzy_score(term: &str, text: &str) -> Option<u32> {
+    let text = text.to_lowercase();
+    let mut chars = text.char_indices().peekable();
+    let mut previous_end = None;
+    let mut score = 0;
+
+    for wanted in term.chars() {
+        let (index, found) = chars.find(|(_, c)| *c == wanted)?;
+        score += 1;
+        if previous_end == Some(index) {
+            score += 2;
+        }
+        if index == 0 || text[..index].ends_with(['.', '/', '-', '_', ' ']) {
+            score += 2;
+        }
+        previous_end = Some(index + found.len_utf8());
+    }
+
+    Some(score)
+}
+
+/// Formats a size in bytes for humans, using decimal units.
+pub(crate) fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["kB", "MB", "GB", "TB"];
+
+    if size < 1000 {
+        return format!("{size} bytes");
+    }
+
+    let mut value = size as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Parses a size label, treating missing or malformed values as unknown.
+fn parse_size(label: Option<&str>) -> Option<u64> {
+    label?.parse().ok()
+}
+
+/// Something that can produce an index: normally the remote registry, but a fixed table of
+/// entries can also be used, which makes the commands testable without network access.
+pub(crate) trait IndexSource {
+    async fn get_index(&self) -> Result<HashMap<Ref, IndexEntry>>;
+}
+
+impl IndexSource for HashMap<Ref, IndexEntry> {
+    async fn get_index(&self) -> Result<HashMap<Ref, IndexEntry>> {
+        Ok(self.clone())
+    }
+}
+
+/// Turns the images in the index into entries, keyed by their ref.  Images that aren't flatpaks or
+/// have an invalid ref are skipped.
+fn build_table(names: Vec<Name>) -> HashMap<Ref, IndexEntry> {
+    let mut table = HashMap::new();
+
+    for name in names {
+        for image in name.images {
+            let (Some(r#ref), Some(metadata)) = (
+                image.label(&["org.flatpak.ref"]),
+                image.label(&["org.flatpak.metadata"]),
+            ) else {
+                log::debug!(
+                    "Skipping {}@{}: it isn't a flatpak",
+                    name.name,
+                    image.digest
+                );
+                continue;
+            };
+
+            let r#ref = match r#ref.parse() {
+                Ok(r#ref) => r#ref,
+                Err(err) => {
+                    log::warn!("Skipping {}@{}: {err}", name.name, image.digest);
+                    continue;
+                }
+            };
+
+            table.insert(
+                r#ref,
+                IndexEntry {
+                    image: format!("{}@{}", name.name, image.digest),
+                    metadata: metadata.to_string(),
+                    download_size: parse_size(image.label(&["org.flatpak.download-size"])),
+                    installed_size: parse_size(image.label(&["org.flatpak.installed-size"])),
+                    // Fedora uses the plain labels, other registries the OCI ones
+                    name: image
+                        .label(&["name", "org.opencontainers.image.title"])
+                        .map(str::to_string),
+                    summary: image
+                        .label(&["summary", "org.opencontainers.image.description"])
+                        .map(str::to_string),
+                    digest: image.digest,
+                },
+            );
+        }
+    }
+
+    table
+}
+
+/// Maps a flatpak (or Rust) architecture name to the name used by OCI registries.  Rust calls
+/// both the big- and little-endian variants powerpc64, so that one depends on what we run on.
+fn get_oci_arch(arch: &str) -> &str {
+    match arch {
         "aarch64" => "arm64",
-        "x86" => "386",
+        "x86" | "i386" => "386",
         "x86_64" => "amd64",
-        other => other,
+        "powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+        "powerpc64" | "ppc64" => "ppc64",
+        "ppc64le" => "ppc64le",
+        "s390x" => "s390x",
+        "riscv64" => "riscv64",
+        other => {
+            log::warn!("Unknown architecture {other}: the index might not have anything for it");
+            other
+        }
     }
 }
 
@@ -53,52 +250,285 @@ fn ensure_cache_path() -> Option<PathBuf> {
     Some(path)
 }
 
-fn create_client() -> ClientWithMiddleware {
-    let mut builder = ClientBuilder::new(Client::new());
+/// Options controlling how the index gets fetched.
+#[derive(Debug)]
+pub(crate) struct IndexOptions<'a> {
+    pub(crate) arch: &'a str,
+    /// Serve the index only from the HTTP cache, never hitting the network.
+    pub(crate) offline: bool,
+    /// Ignore the HTTP cache and fetch the index again.
+    pub(crate) refresh: bool,
+    /// How many times to retry after connection errors or server errors.
+    pub(crate) retries: u32,
+    /// Timeout for each attempt at fetching the index.
+    pub(crate) timeout: Duration,
+    /// A PEM file with CA certificates to trust in addition to the ones of the system.
+    pub(crate) ca_cert: Option<&'a Path>,
+}
+
+/// Creates the client for fetching the index from `repository`, with its credentials (if we
+/// have any) sent along with every request.
+fn create_client(
+    repository: &Url,
+    mode: CacheMode,
+    options: &IndexOptions<'_>,
+) -> Result<ClientWithMiddleware> {
+    let mut headers = HeaderMap::new();
+    if let Some(authorization) = registry_authorization(repository)? {
+        headers.insert(AUTHORIZATION, authorization);
+    }
+
+    let mut client = Client::builder()
+        .connect_timeout(options.timeout)
+        .timeout(options.timeout)
+        .default_headers(headers);
+
+    if let Some(path) = options.ca_cert {
+        let pem = std::fs::read(path).with_context(|| format!("Unable to read {path:?}"))?;
+        let certificates = Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid CA certificates in {path:?}"))?;
+        for certificate in certificates {
+            client = client.add_root_certificate(certificate);
+        }
+    }
+
+    let client = client.build().context("Unable to create HTTP client")?;
+
+    let mut builder = ClientBuilder::new(client);
 
     if let Some(path) = ensure_cache_path() {
         builder = builder.with(Cache(HttpCache {
-            mode: CacheMode::Default,
+            mode,
             manager: CACacheManager { path },
             options: HttpCacheOptions::default(),
         }));
     }
 
-    builder.build()
+    Ok(builder.build())
 }
 
-pub(crate) async fn get_index(repository: &str) -> Result<HashMap<Ref, (String, String)>> {
+/// If the response was served from the cache, returns how long ago it was originally fetched.
+fn cache_age(headers: &HeaderMap) -> Option<Duration> {
+    // http-cache marks responses that it served itself with this header
+    if headers.get("x-cache")? != "HIT" {
+        return None;
+    }
+
+    let date = httpdate::parse_http_date(headers.get(DATE)?.to_str().ok()?).ok()?;
+    SystemTime::now().duration_since(date).ok()
+}
+
+/// Sends a GET request, retrying with exponential backoff on connection errors and 5xx responses.
+/// Client errors (4xx) are returned immediately: they won't fix themselves.
+async fn get_with_retries(
+    client: &ClientWithMiddleware,
+    url: &Url,
+    retries: u32,
+    timeout: Duration,
+) -> Result<Response> {
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 0;
+
+    loop {
+        // The reqwest timeout only covers the network part, but this includes the cache layer
+        let result = tokio::time::timeout(timeout, client.get(url.clone()).send()).await;
+
+        let retryable = match &result {
+            Err(_elapsed) => true,
+            Ok(Ok(response)) => response.status().is_server_error(),
+            Ok(Err(err)) => err.is_connect() || err.is_timeout(),
+        };
+
+        if !retryable || attempt >= retries {
+            return match result {
+                Err(_elapsed) => bail!("Timed out fetching index from {url}"),
+                Ok(Err(err)) if err.is_timeout() => {
+                    Err(err).with_context(|| format!("Timed out fetching index from {url}"))
+                }
+                Ok(result) => Ok(result?),
+            };
+        }
+
+        match result {
+            Err(_elapsed) => log::warn!("Fetching {url} timed out"),
+            Ok(Ok(response)) => log::warn!("Fetching {url} failed: {}", response.status()),
+            Ok(Err(err)) => log::warn!("Fetching {url} failed: {err}"),
+        }
+        attempt += 1;
+        log::warn!("Retrying in {delay:?} ({attempt} of {retries})");
+
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+fn index_url(repository: &str, arch: &str, name: Option<&str>) -> Result<Url> {
     let mut index = Url::parse(repository)?.join("index/static")?;
 
     let mut pairs = index.query_pairs_mut();
-    pairs.append_pair("architecture", get_oci_arch());
+    pairs.append_pair("architecture", get_oci_arch(arch));
     pairs.append_pair("label:org.flatpak.ref:exists", "1");
     pairs.append_pair("os", "linux");
+    if let Some(name) = name {
+        pairs.append_pair("repository", name);
+    }
     pairs.append_pair("tag", "latest");
     drop(pairs);
 
-    let response: IndexResponse = create_client()
-        .get(index)
-        .send()
-        .await?
+    Ok(index)
+}
+
+/// Fetches and parses one index document, returning it along with its age, if it was cached.
+async fn fetch_index(
+    client: &ClientWithMiddleware,
+    url: &Url,
+    options: &IndexOptions<'_>,
+) -> Result<(IndexResponse, Option<Duration>)> {
+    // The 504 we get for a cache miss in offline mode isn't worth retrying
+    let retries = if options.offline { 0 } else { options.retries };
+    let response = get_with_retries(client, url, retries, options.timeout).await?;
+
+    // http-cache answers with 504 Gateway Timeout when OnlyIfCached misses
+    if options.offline && response.status() == StatusCode::GATEWAY_TIMEOUT {
+        bail!("The index is not available in the cache: try again without --offline");
+    }
+
+    let age = cache_age(response.headers());
+
+    let response: IndexResponse = response
         .error_for_status()?
         .json()
         .await
         .context("Parsing index JSON failed")?;
 
-    let mut table = HashMap::new();
+    Ok((response, age))
+}
 
-    for name in response.results {
-        for image in name.images {
-            table.insert(
-                image.labels.r#ref,
-                (
-                    format!("{}@{}", name.name, image.digest),
-                    image.labels.metadata,
-                ),
-            );
+pub(crate) async fn get_index(
+    repository: &str,
+    options: &IndexOptions<'_>,
+) -> Result<HashMap<Ref, IndexEntry>> {
+    let mode = if options.offline {
+        if ensure_cache_path().is_none() {
+            bail!("Unable to use --offline without a cache directory");
         }
+        CacheMode::OnlyIfCached
+    } else if options.refresh {
+        CacheMode::Reload
+    } else {
+        CacheMode::Default
+    };
+
+    let client = create_client(&Url::parse(repository)?, mode, options)?;
+    let index = index_url(repository, options.arch, None)?;
+    let (response, age) = fetch_index(&client, &index, options).await?;
+
+    if let Some(age) = age {
+        let minutes = age.as_secs() / 60;
+        eprintln!("Using cached index from {minutes} minutes ago (use --refresh to update)");
+    }
+
+    // Registries like Fedora's inline all of the images in the index, but others only list the
+    // names.  Fetch the images for those names separately, a few at a time.
+    let (mut names, partial): (Vec<_>, Vec<_>) = response
+        .results
+        .into_iter()
+        .partition(|name| !name.images.is_empty());
+
+    let client = &client;
+    let mut fetches = stream::iter(partial)
+        .map(|name| async move {
+            let url = index_url(repository, options.arch, Some(&name.name))?;
+            let (response, _) = fetch_index(client, &url, options)
+                .await
+                .with_context(|| format!("Fetching images for {}", name.name))?;
+            anyhow::Ok(response.results)
+        })
+        .buffer_unordered(CONCURRENT_FETCHES);
+
+    while let Some(results) = fetches.try_next().await? {
+        names.extend(results);
+    }
+
+    Ok(build_table(names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn broken_entries_are_skipped() {
+        let json = r#"{
+            "Results": [
+                {
+                    "Name": "good",
+                    "Images": [
+                        {
+                            "Digest": "sha256:1",
+                            "Labels": {
+                                "org.flatpak.ref": "app/org.example.Good/x86_64/stable",
+                                "org.flatpak.metadata": "[Application]\nname=org.example.Good\n",
+                                "name": "Good"
+                            }
+                        },
+                        {"Digest": "sha256:2", "Labels": {"org.flatpak.ref": 5}},
+                        {
+                            "Digest": "sha256:3",
+                            "Labels": {
+                                "org.flatpak.ref": "not/a/ref",
+                                "org.flatpak.metadata": "[Application]\nname=Bad\n"
+                            }
+                        },
+                        {
+                            "Digest": "sha256:4",
+                            "Annotations": {
+                                "org.flatpak.ref": "runtime/org.example.Platform/x86_64/1",
+                                "org.flatpak.metadata": "[Runtime]\nname=org.example.Platform\n"
+                            }
+                        }
+                    ]
+                },
+                {"Images": []}
+            ]
+        }"#;
+
+        let response: IndexResponse = serde_json::from_str(json).unwrap();
+        assert_eq!(response.results.len(), 1);
+
+        let table = build_table(response.results);
+        assert_eq!(table.len(), 2);
+
+        let good = &table["app/org.example.Good/x86_64/stable"];
+        assert_eq!(good.image, "good@sha256:1");
+        assert_eq!(good.name.as_deref(), Some("Good"));
+        assert_eq!(
+            table["runtime/org.example.Platform/x86_64/1"].digest,
+            "sha256:4"
+        );
     }
 
-    Ok(table)
+    #[test]
+    fn oci_arch() {
+        for (arch, oci_arch) in [
+            ("aarch64", "arm64"),
+            ("x86", "386"),
+            ("i386", "386"),
+            ("x86_64", "amd64"),
+            ("ppc64le", "ppc64le"),
+            ("ppc64", "ppc64"),
+            ("s390x", "s390x"),
+            ("riscv64", "riscv64"),
+            ("mips64", "mips64"),
+        ] {
+            assert_eq!(get_oci_arch(arch), oci_arch, "{arch}");
+        }
+
+        let powerpc64 = if cfg!(target_endian = "little") {
+            "ppc64le"
+        } else {
+            "ppc64"
+        };
+        assert_eq!(get_oci_arch("powerpc64"), powerpc64);
+    }
 }
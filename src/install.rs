@@ -1,71 +1,967 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    pin::pin,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{manifest::Manifest, r#ref::Ref};
-use anyhow::{Result, bail};
-use composefs::{fsverity::FsVerityHashValue, repository::Repository};
-use rustix::fs::{AtFlags, unlinkat};
+use crate::{
+    export::{export_desktop_files, remove_desktop_files},
+    index::{IndexEntry, format_size},
+    manifest::Manifest,
+    output::{format_ref, warning},
+    pin::unpin,
+    prune::{OldImage, prune_old_images},
+    r#ref::{Ref, RefKind},
+    sandbox::remove_ld_cache,
+};
+use anyhow::{Context, Result, bail};
+use composefs::{
+    fsverity::{FsVerityHashValue, measure_verity},
+    repository::Repository,
+    tree::{Directory, FileSystem, Inode, LeafContent, RegularFile},
+};
+use futures::{
+    StreamExt, TryStreamExt,
+    future::{Either, select, try_join},
+    stream,
+};
+use rustix::{
+    fd::OwnedFd,
+    fs::{
+        AtFlags, Dir, FlockOperation, Mode, OFlags, flock, mkdirat, openat, readlinkat, statat,
+        symlinkat, unlinkat,
+    },
+    io::Errno,
+};
+use serde::Serialize;
 
+/// Options controlling how refs get installed.
+#[derive(Debug, Default)]
+pub(crate) struct InstallOptions {
+    /// Remove the images that got replaced by the installation, as far as nothing else uses them.
+    pub(crate) prune_old: bool,
+    /// Pull the image again, even if the installed version is current.
+    pub(crate) reinstall: bool,
+    /// Check the fsverity digests of all of the objects after installing.
+    pub(crate) verify: bool,
+    /// Don't export the desktop files and icons of apps to the host.
+    pub(crate) no_desktop: bool,
+}
+
+/// What happened to a ref during an installation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    /// The ref wasn't installed before, or was pulled again with the same contents.
+    Installed,
+    /// The ref replaced an older version.
+    Updated,
+    /// The installed version was already current, so nothing was pulled.
+    Current,
+}
+
+/// How many images to pull at the same time during an update
+const CONCURRENT_PULLS: usize = 4;
+
+/// How often to report download progress
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Download progress, as reported to the callback passed to [`install`] and [`install_oci`].
+/// `written` counts the size of the objects that the pulls added to the repository so far.
+pub(crate) enum Progress {
+    /// Still downloading.  `expected` is the installed size from the index, if known.
+    Downloading { written: u64, expected: Option<u64> },
+    /// Another layer was completed, making it `layers` of them so far.
+    Layer { layers: usize, written: u64 },
+    /// The pulls finished.
+    Finished { written: u64 },
+}
+
+pub(crate) fn open_dir(dirfd: &OwnedFd, name: &str) -> rustix::io::Result<OwnedFd> {
+    let flags = OFlags::RDONLY | OFlags::DIRECTORY | OFlags::CLOEXEC;
+    openat(dirfd, name, flags, Mode::empty())
+}
+
+/// Collects the refs below a directory in the stream refs tree.  The refs are stored as
+/// kind/id/arch/branch so we find them as symlinks at depth 3.
+pub(crate) fn collect_refs(
+    dirfd: &OwnedFd,
+    prefix: &str,
+    depth: usize,
+    refs: &mut Vec<Ref>,
+) -> Result<()> {
+    for entry in Dir::read_from(dirfd)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().to_str() else {
+            continue;
+        };
+
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}/{name}")
+        };
+
+        if depth == 3 {
+            match path.parse() {
+                Ok(r#ref) => refs.push(r#ref),
+                Err(err) => log::warn!("Ignoring unexpected stream ref {path}: {err}"),
+            }
+        } else {
+            collect_refs(&open_dir(dirfd, name)?, &path, depth + 1, refs)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the installed refs.
+pub(crate) fn installed_refs<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+) -> Result<Vec<Ref>> {
+    let dirfd = match open_dir(repo.objects_dir()?, "../streams/refs/flatpak-rs") {
+        Ok(dirfd) => dirfd,
+        Err(Errno::NOENT) => return Ok(vec![]),
+        Err(err) => Err(err).context("Unable to open stream refs directory")?,
+    };
+
+    let mut refs = vec![];
+    collect_refs(&dirfd, "", 0, &mut refs)?;
+    refs.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    Ok(refs)
+}
+
+/// Reads the target of the stream ref for the given flatpak ref, if it exists.
+pub(crate) fn read_stream_ref<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<Option<String>> {
+    match readlinkat(
+        repo.objects_dir()?,
+        format!("../streams/refs/flatpak-rs/{ref}"),
+        vec![],
+    ) {
+        Ok(target) => Ok(Some(target.to_string_lossy().into_owned())),
+        Err(Errno::NOENT) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Unable to read stream ref for {ref}")),
+    }
+}
+
+/// Points the stream ref for the given flatpak ref back at `target`, or removes it if None.
+fn restore_stream_ref<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+    target: Option<&str>,
+) -> Result<()> {
+    let dirfd = repo.objects_dir()?;
+    let path = format!("../streams/refs/flatpak-rs/{ref}");
+
+    match unlinkat(dirfd, &path, AtFlags::empty()) {
+        Ok(()) | Err(Errno::NOENT) => {}
+        Err(err) => Err(err).with_context(|| format!("Unable to remove stream ref for {ref}"))?,
+    }
+    if let Some(target) = target {
+        symlinkat(target, dirfd, &path)
+            .with_context(|| format!("Unable to restore stream ref for {ref}"))?;
+    }
+
+    Ok(())
+}
+
+/// The index digests of installed refs are recorded as symlinks (pointing at the digest) in our
+/// own directory next to the repository, so we can tell if an install is up to date without
+/// asking the registry.  The config digest in the stream ref can't be compared to the index.
+fn digest_path(r#ref: &Ref) -> String {
+    format!("../flatpak-rs/digests/{ref}")
+}
+
+/// Reads the index digest that the given ref was installed from, if it's known.
+pub(crate) fn installed_digest<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<Option<String>> {
+    match readlinkat(repo.objects_dir()?, digest_path(r#ref), vec![]) {
+        Ok(target) => Ok(Some(target.to_string_lossy().into_owned())),
+        Err(Errno::NOENT) => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Unable to read digest of {ref}")),
+    }
+}
+
+/// Creates the parent directories of a path like `../flatpak-rs/...`, skipping the leading "..".
+pub(crate) fn create_parents(dirfd: &OwnedFd, path: &str) -> Result<()> {
+    for (end, _) in path.match_indices('/').skip(1) {
+        match mkdirat(dirfd, &path[..end], Mode::from(0o755)) {
+            Ok(()) | Err(Errno::EXIST) => {}
+            Err(err) => Err(err).with_context(|| format!("Unable to create {}", &path[..end]))?,
+        }
+    }
+
+    Ok(())
+}
+
+/// A lock on the repository, in our own directory next to it.  Installations and sandboxes hold
+/// it shared, since they use objects that might not be referenced yet, or that an update is about
+/// to replace.  Pruning old images takes it exclusively, because it removes objects directly.
+pub(crate) struct RepoLock {
+    _fd: OwnedFd,
+}
+
+impl RepoLock {
+    fn open(objects_dir: &OwnedFd) -> Result<OwnedFd> {
+        let path = "../flatpak-rs/lock";
+        create_parents(objects_dir, path)?;
+        let flags = OFlags::RDWR | OFlags::CREATE | OFlags::CLOEXEC;
+        openat(objects_dir, path, flags, Mode::from(0o644)).context("Unable to open lock file")
+    }
+
+    fn try_lock(objects_dir: &OwnedFd, operation: FlockOperation) -> Result<Option<Self>> {
+        let fd = Self::open(objects_dir)?;
+        match flock(&fd, operation) {
+            Ok(()) => Ok(Some(Self { _fd: fd })),
+            Err(Errno::WOULDBLOCK) => Ok(None),
+            Err(err) => Err(err).context("Unable to lock the repository"),
+        }
+    }
+
+    /// Waits for a shared lock.
+    pub(crate) fn shared<ObjectID: FsVerityHashValue>(repo: &Repository<ObjectID>) -> Result<Self> {
+        let fd = Self::open(repo.objects_dir()?)?;
+        flock(&fd, FlockOperation::LockShared).context("Unable to lock the repository")?;
+        Ok(Self { _fd: fd })
+    }
+
+    /// Takes an exclusive lock, or returns None if anyone else holds the lock.
+    pub(crate) fn try_exclusive<ObjectID: FsVerityHashValue>(
+        repo: &Repository<ObjectID>,
+    ) -> Result<Option<Self>> {
+        Self::try_lock(
+            repo.objects_dir()?,
+            FlockOperation::NonBlockingLockExclusive,
+        )
+    }
+}
+
+/// Records the index digest that the given ref was installed from, or forgets it if None.
+fn record_digest<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+    digest: Option<&str>,
+) -> Result<()> {
+    let dirfd = repo.objects_dir()?;
+    let path = digest_path(r#ref);
+
+    match unlinkat(dirfd, &path, AtFlags::empty()) {
+        Ok(()) | Err(Errno::NOENT) => {}
+        Err(err) => Err(err).with_context(|| format!("Unable to remove digest of {ref}"))?,
+    }
+
+    if let Some(digest) = digest {
+        create_parents(dirfd, &path)?;
+        symlinkat(digest, dirfd, &path)
+            .with_context(|| format!("Unable to record digest of {ref}"))?;
+    }
+
+    Ok(())
+}
+
+/// Checks if the given ref is installed.
+pub(crate) fn is_installed<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<bool> {
+    Ok(read_stream_ref(repo, r#ref)?.is_some())
+}
+
+/// Reads the filesystem tree of an installed ref, or None if the ref isn't installed.
+pub(crate) fn installed_filesystem<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<Option<FileSystem<ObjectID>>> {
+    if !is_installed(repo, r#ref)? {
+        return Ok(None);
+    }
+
+    let name = format!("refs/flatpak-rs/{ref}");
+    Ok(Some(composefs_oci::image::create_filesystem(
+        repo, &name, None,
+    )?))
+}
+
+/// Something that's needed to run a ref, for `info --dependencies`.
+#[derive(Debug, Serialize)]
+pub(crate) struct Dependency {
+    #[serde(rename = "ref")]
+    pub(crate) r#ref: Ref,
+    /// `runtime`, `sdk` or `extension`
+    pub(crate) kind: &'static str,
+    pub(crate) installed: bool,
+}
+
+/// Finds everything that's needed to run `ref`: the runtime of an app, the SDK, and the
+/// extensions that get installed by default, of the ref and of its runtime.  The manifests come
+/// from the installed refs if we have them, and otherwise from the index.
+pub(crate) fn find_dependencies<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    index: &HashMap<Ref, IndexEntry>,
+    r#ref: &Ref,
+) -> Result<Vec<Dependency>> {
+    let manifest_of = |r#ref: &Ref| -> Result<Option<Manifest>> {
+        match installed_manifest(repo, r#ref)? {
+            Some(manifest) => Ok(Some(manifest)),
+            None => index
+                .get(r#ref)
+                .map(|entry| Manifest::new(&entry.metadata))
+                .transpose(),
+        }
+    };
+    let manifest = manifest_of(r#ref)?.with_context(|| format!("No such ref {ref}"))?;
+    let installed = installed_refs(repo)?;
+
+    // The refs, along with whether they're extensions with subdirectories
+    let mut refs = vec![];
+    let runtime = if r#ref.is_app() {
+        let runtime = manifest.get_runtime()?;
+        refs.push((runtime.clone(), "runtime", false));
+        match manifest_of(&runtime)? {
+            Some(manifest) => Some((runtime, manifest)),
+            None => {
+                log::debug!("Not listing the extensions of {runtime}: it's not in the index");
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(sdk) = manifest.get_sdk() {
+        refs.push((sdk.clone(), "sdk", false));
+    }
+    for (r#ref, manifest) in [(r#ref, &manifest)].into_iter().chain(
+        runtime
+            .as_ref()
+            .map(|(runtime, manifest)| (runtime, manifest)),
+    ) {
+        for extension in manifest.get_extensions() {
+            if !extension.autodownload {
+                continue;
+            }
+            let version = extension.version.as_deref().unwrap_or(r#ref.get_branch());
+            let arch = r#ref.get_arch();
+            match Ref::try_from(format!("runtime/{}/{arch}/{version}", extension.name)) {
+                Ok(extension_ref) => {
+                    refs.push((extension_ref, "extension", extension.subdirectories))
+                }
+                Err(err) => log::debug!("Ignoring extension {}: {err}", extension.name),
+            }
+        }
+    }
+
+    let mut dependencies: Vec<Dependency> = vec![];
+    for (r#ref, kind, subdirectories) in refs {
+        if dependencies
+            .iter()
+            .any(|dependency| dependency.r#ref == r#ref)
+        {
+            continue;
+        }
+        // Extensions with subdirectories can also be installed as several refs, named like
+        // org.freedesktop.Platform.GL.default
+        let prefix = format!("{}.", r#ref.get_id());
+        let installed = installed.iter().any(|other| {
+            *other == r#ref
+                || (subdirectories
+                    && other.get_id().starts_with(&prefix)
+                    && other.get_arch() == r#ref.get_arch()
+                    && other.get_branch() == r#ref.get_branch())
+        });
+        dependencies.push(Dependency {
+            r#ref,
+            kind,
+            installed,
+        });
+    }
+
+    Ok(dependencies)
+}
+
+/// Reads the manifest embedded in an installed ref, or None if the ref isn't installed.
+pub(crate) fn installed_manifest<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<Option<Manifest>> {
+    match installed_filesystem(repo, r#ref)? {
+        Some(filesystem) => Ok(Some(Manifest::from_filesystem(repo, &filesystem)?)),
+        None => Ok(None),
+    }
+}
+
+fn print_download_size(entry: &IndexEntry) {
+    if let Some(size) = entry.download_size {
+        println!("Download size {}", format_size(size));
+    }
+}
+
+/// Turns an index entry into a `docker://` image reference that we can pull.
+fn image_ref(img_base: &str, entry: &IndexEntry) -> String {
+    let mut img_ref = img_base.replace("https", "docker");
+    img_ref.push_str(&entry.image);
+    img_ref
+}
+
+/// Keeps track of what the pulls add to the repository.  Objects only show up once they're
+/// complete (they're written to a temporary file and linked into place) and composefs names the
+/// stream of each layer once all of its files are in, so that's what we look for.
+struct PullTracker {
+    objects_dir: OwnedFd,
+    objects: HashSet<String>,
+    streams: HashSet<String>,
+    /// False during the first scan, which only takes note of what's there already
+    counting: bool,
+    written: u64,
+    layers: usize,
+}
+
+impl PullTracker {
+    /// Takes note of what's already in the repository with the given objects directory.
+    fn new(objects_dir: OwnedFd) -> Result<Self> {
+        let mut tracker = Self {
+            objects_dir,
+            objects: HashSet::new(),
+            streams: HashSet::new(),
+            counting: false,
+            written: 0,
+            layers: 0,
+        };
+        tracker.scan()?;
+        tracker.counting = true;
+        Ok(tracker)
+    }
+
+    /// Looks for new objects and streams, adding them to the totals.
+    fn scan(&mut self) -> Result<()> {
+        for entry in Dir::read_from(&self.objects_dir)? {
+            let entry = entry?;
+            let Ok(prefix) = entry.file_name().to_str() else {
+                continue;
+            };
+            if prefix.len() != 2 || prefix == ".." {
+                continue;
+            }
+
+            let subdir = open_dir(&self.objects_dir, prefix)?;
+            for entry in Dir::read_from(&subdir)? {
+                let entry = entry?;
+                let Ok(name) = entry.file_name().to_str() else {
+                    continue;
+                };
+                if name.starts_with('.')
+                    || !self.objects.insert(format!("{prefix}{name}"))
+                    || !self.counting
+                {
+                    continue;
+                }
+                // Not finding it means that something else removed it in the meantime
+                if let Ok(stat) = statat(&subdir, name, AtFlags::SYMLINK_NOFOLLOW) {
+                    self.written += stat.st_size as u64;
+                }
+            }
+        }
+
+        let streams = match open_dir(&self.objects_dir, "../streams") {
+            Ok(streams) => streams,
+            Err(Errno::NOENT) => return Ok(()),
+            Err(err) => Err(err).context("Unable to open streams directory")?,
+        };
+        for entry in Dir::read_from(&streams)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            if !matches!(name, "." | ".." | "refs")
+                && self.streams.insert(name.to_string())
+                && self.counting
+            {
+                self.layers += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs some downloads, reporting progress while they run.  composefs_oci doesn't tell us how far
+/// along it is, so we look at the objects and streams that show up in the repository instead.
+/// The stream of the config shows up at the very end, so it might get counted as a layer too.
+async fn with_progress<ObjectID: FsVerityHashValue, T>(
+    repo: &Repository<ObjectID>,
+    expected: Option<u64>,
+    progress: &impl Fn(Progress),
+    work: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let tracker = RefCell::new(PullTracker::new(repo.objects_dir()?.try_clone()?)?);
+    let scan = || {
+        let mut tracker = tracker.borrow_mut();
+        let layers = tracker.layers;
+        if let Err(err) = tracker.scan() {
+            log::debug!("Unable to check the progress of the download: {err:#}");
+        }
+        (tracker.layers > layers).then_some(tracker.layers)
+    };
+
+    let poll = pin!(async {
+        loop {
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+            let layers = scan();
+            let written = tracker.borrow().written;
+            if let Some(layers) = layers {
+                progress(Progress::Layer { layers, written });
+            }
+            progress(Progress::Downloading { written, expected });
+        }
+    });
+
+    let result = match select(pin!(work), poll).await {
+        Either::Left((result, _)) => result?,
+        Either::Right(((), _)) => unreachable!("the progress loop never finishes"),
+    };
+
+    scan();
+    progress(Progress::Finished {
+        written: tracker.borrow().written,
+    });
+    Ok(result)
+}
+
+/// Collects the IDs of the external objects referenced by the files below a directory.
+pub(crate) fn collect_objects<ObjectID: FsVerityHashValue>(
+    dir: &Directory<ObjectID>,
+    objects: &mut HashSet<ObjectID>,
+) {
+    for (_, inode) in dir.entries() {
+        match inode {
+            Inode::Directory(subdir) => collect_objects(subdir, objects),
+            Inode::Leaf(leaf) => {
+                if let LeafContent::Regular(RegularFile::External(id, ..)) = &leaf.content {
+                    objects.insert(id.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Measures each object and checks that its fsverity digest matches the ID it's stored under.
+fn verify_objects<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    objects: &HashSet<ObjectID>,
+) -> Result<()> {
+    println!(">>> Verifying {} objects", objects.len());
+
+    for id in objects {
+        let fd = repo
+            .open_object(id)
+            .with_context(|| format!("Unable to open object {}", id.to_hex()))?;
+        let measured: ObjectID = measure_verity(&fd)
+            .with_context(|| format!("Unable to measure object {}", id.to_hex()))?;
+        if measured != *id {
+            bail!(
+                "Object {} is corrupt: its fsverity digest is {}",
+                id.to_hex(),
+                measured.to_hex()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Installs a single image.  If we know the index digest of the image and it's the one we already
+/// have, nothing gets pulled, unless `options.reinstall` is set.  With `options.verify`, the
+/// fsverity digests of all of the objects in the image are checked afterwards.  With
+/// `options.prune_old`, an update also returns the image that it replaced.
 async fn install_one<ObjectID: FsVerityHashValue>(
     repo: &Arc<Repository<ObjectID>>,
     r#ref: &Ref,
-    img_base: &str,
-    img: &str,
-) -> Result<String> {
-    let mut img_ref = img_base.replace("https", "docker");
-    img_ref.push_str(img);
+    img_ref: &str,
+    index_digest: Option<&str>,
+    options: &InstallOptions,
+) -> Result<(Outcome, Option<OldImage<ObjectID>>)> {
+    let previous = read_stream_ref(repo, r#ref)?;
+
+    if !options.reinstall
+        && previous.is_some()
+        && index_digest.is_some()
+        && installed_digest(repo, r#ref)?.as_deref() == index_digest
+    {
+        println!("{ref} is already up to date");
+        return Ok((Outcome::Current, None));
+    }
+
+    // Pruning the old image needs to know what was in it, which we can't find out after the pull
+    let old_image = if options.prune_old {
+        OldImage::read(repo, r#ref).unwrap_or_else(|err| {
+            warning(format!(
+                "Unable to read the installed image of {ref}: {err:#}"
+            ));
+            None
+        })
+    } else {
+        None
+    };
 
     println!(">>> Downloading from {img_ref}");
 
-    // HACK: We don't want to hear that we already have a reference with a given name, so unlink it
-    // ahead of time in case it already exists... it's just a symlink (and the container config is
-    // content addressed) so we won't actually redownload anything if we're already up to date...
+    // The pull refuses to replace an existing reference, so unlink it ahead of time.  It's just a
+    // symlink (and the container config is content addressed) so we won't actually redownload
+    // anything we already have.
     let _ = unlinkat(
         repo.objects_dir()?,
         format!("../streams/refs/flatpak-rs/{ref}"),
         AtFlags::empty(),
     );
 
-    let (digest, verity) =
-        composefs_oci::pull(repo, &img_ref, Some(&format!("flatpak-rs/{ref}"))).await?;
+    // containers/image finds the credentials for the registry on its own, in the same places that
+    // we look for them to fetch the index
+    let name = format!("flatpak-rs/{ref}");
+    let (digest, verity) = composefs_oci::pull(repo, img_ref, Some(&name)).await?;
 
     println!("config {}", hex::encode(digest));
     println!("verity {}", verity.to_hex());
 
     let mut fs =
         composefs_oci::image::create_filesystem(repo, &hex::encode(digest), Some(&verity))?;
+
+    // Make sure that we got an image of what we asked for before we commit it.  The pull only
+    // tells us the digest of the config, not the one of the manifest that the index names, so
+    // the metadata is all that we can check.
+    let manifest = Manifest::from_filesystem(repo, &fs)?;
+    let actual = manifest.get_ref(r#ref.get_arch(), r#ref.get_branch())?;
+    if actual != *r#ref {
+        restore_stream_ref(repo, r#ref, previous.as_deref())?;
+        bail!("{img_ref} contains {actual}, not {ref}");
+    }
+
     let image_id = fs.commit_image(repo, None)?;
 
     println!("image {}", image_id.to_hex());
 
-    Ok(hex::encode(digest))
+    if r#ref.is_app() && !options.no_desktop {
+        if let Err(err) = export_desktop_files(repo, r#ref, &fs) {
+            warning(format!("Unable to export desktop files of {ref}: {err:#}"));
+        }
+    }
+
+    if options.verify {
+        let mut objects = HashSet::from([verity, image_id]);
+        collect_objects(&fs.root, &mut objects);
+        verify_objects(repo, &objects).with_context(|| format!("Verifying {ref} failed"))?;
+    }
+
+    record_digest(repo, r#ref, index_digest)?;
+
+    if previous.is_some() && previous != read_stream_ref(repo, r#ref)? {
+        Ok((Outcome::Updated, old_image))
+    } else {
+        Ok((Outcome::Installed, None))
+    }
 }
 
 pub async fn install<ObjectID: FsVerityHashValue>(
     repo: &Arc<Repository<ObjectID>>,
     img_base: &str,
-    index: &HashMap<Ref, (String, String)>,
+    index: &HashMap<Ref, IndexEntry>,
     r#ref: &Ref,
-) -> Result<(Option<String>, String)> {
-    let Some((img, manifest)) = index.get(r#ref) else {
+    options: &InstallOptions,
+    progress: &impl Fn(Progress),
+) -> Result<()> {
+    let Some(entry) = index.get(r#ref) else {
         bail!("No such ref {ref}");
     };
+    let lock = RepoLock::shared(repo)?;
 
-    println!("First manifest {manifest:?}");
-    let first = install_one(repo, r#ref, img_base, img).await?;
+    println!("First manifest {:?}", entry.metadata);
+    print_download_size(entry);
+    let img_ref = image_ref(img_base, entry);
+    let mut expected = entry.installed_size;
 
-    let (app, runtime) = if r#ref.is_runtime() {
-        (None, first)
-    } else {
-        let manifest = Manifest::new(manifest)?;
-        let runtime = manifest.get_runtime()?;
-        let Some((runtime_img, runtime_manifest)) = index.get(&runtime) else {
-            bail!("No such ref {ref}");
+    let runtime = match r#ref.get_kind() {
+        RefKind::Runtime => None,
+        RefKind::App => {
+            let manifest = Manifest::new(&entry.metadata)?;
+            let runtime = manifest.get_runtime()?;
+            let Some(runtime_entry) = index.get(&runtime) else {
+                bail!("No such ref {runtime}");
+            };
+
+            println!("Linked runtime manifest {:?}", runtime_entry.metadata);
+            print_download_size(runtime_entry);
+            expected = expected
+                .zip(runtime_entry.installed_size)
+                .map(|(app, runtime)| app + runtime);
+            Some((runtime, runtime_entry))
+        }
+    };
+
+    // The app and the runtime are independent, so download them at the same time.  Writing to the
+    // repository concurrently is fine: objects are content addressed and get linked into place
+    // atomically, and the two images have their own stream refs.
+    let first = install_one(repo, r#ref, &img_ref, Some(&entry.digest), options);
+    let second = async {
+        match runtime {
+            Some((runtime, entry)) => {
+                let img_ref = image_ref(img_base, entry);
+                install_one(repo, &runtime, &img_ref, Some(&entry.digest), options).await
+            }
+            None => Ok((Outcome::Current, None)),
+        }
+    };
+
+    let ((_, first), (_, second)) =
+        with_progress(repo, expected, progress, try_join(first, second)).await?;
+    let superseded: Vec<_> = first.into_iter().chain(second).collect();
+    drop(lock);
+
+    if !superseded.is_empty() {
+        println!(">>> Pruning superseded images");
+        prune_old_images(repo, superseded)
+            .context("Installation succeeded, but pruning superseded images failed")?;
+    }
+
+    Ok(())
+}
+
+/// Updates all installed refs whose digest in the index changed.  Apps that moved to a runtime we
+/// don't have yet get it installed as well.
+pub async fn update<ObjectID: FsVerityHashValue>(
+    repo: &Arc<Repository<ObjectID>>,
+    img_base: &str,
+    index: &HashMap<Ref, IndexEntry>,
+    options: &InstallOptions,
+    progress: &impl Fn(Progress),
+) -> Result<()> {
+    let lock = RepoLock::shared(repo)?;
+    let installed = installed_refs(repo)?;
+    let mut current = vec![];
+    let mut missing = vec![];
+    let mut pending: Vec<(Ref, &IndexEntry)> = vec![];
+
+    for r#ref in &installed {
+        let Some(entry) = index.get(r#ref) else {
+            missing.push(r#ref);
+            continue;
         };
 
-        println!("Linked runtime manifest {runtime_manifest:?}");
-        let runtime = install_one(repo, &runtime, img_base, runtime_img).await?;
-        (Some(first), runtime)
+        if installed_digest(repo, r#ref)?.as_deref() == Some(&entry.digest) {
+            current.push(r#ref);
+        } else {
+            pending.push((r#ref.clone(), entry));
+        }
+
+        if r#ref.is_app() {
+            let runtime = Manifest::new(&entry.metadata)?.get_runtime()?;
+            if installed.contains(&runtime) || pending.iter().any(|(r, _)| *r == runtime) {
+                continue;
+            }
+            match index.get(&runtime) {
+                Some(runtime_entry) => pending.push((runtime, runtime_entry)),
+                None => warning(format!("{ref} needs {runtime}, which is not available")),
+            }
+        }
+    }
+
+    let expected = pending.iter().map(|(_, entry)| entry.installed_size).sum();
+    let pulls = stream::iter(&pending)
+        .map(|(r#ref, entry)| async move {
+            let img_ref = image_ref(img_base, entry);
+            let (outcome, old_image) =
+                install_one(repo, r#ref, &img_ref, Some(&entry.digest), options).await?;
+            anyhow::Ok((r#ref, outcome, old_image))
+        })
+        .buffer_unordered(CONCURRENT_PULLS)
+        .try_collect::<Vec<_>>();
+    let outcomes = with_progress(repo, expected, progress, pulls).await?;
+
+    let mut superseded = vec![];
+    for (r#ref, outcome, old_image) in outcomes {
+        superseded.extend(old_image);
+        match outcome {
+            Outcome::Updated => {
+                println!("Updated {}", format_ref(r#ref));
+            }
+            Outcome::Installed if !installed.contains(r#ref) => {
+                println!("Installed {}", format_ref(r#ref));
+            }
+            // We didn't know the digest, but the image turned out to be the same
+            Outcome::Installed | Outcome::Current => current.push(r#ref),
+        }
+    }
+
+    for r#ref in &current {
+        println!("{} is already up to date", format_ref(r#ref));
+    }
+    for r#ref in &missing {
+        println!("{} is no longer in the repository", format_ref(r#ref));
+    }
+    drop(lock);
+
+    if !superseded.is_empty() {
+        println!(">>> Pruning superseded images");
+        prune_old_images(repo, superseded)
+            .context("Update succeeded, but pruning superseded images failed")?;
+    }
+
+    Ok(())
+}
+
+/// Installs an image directly from an image reference like `docker://registry/name@sha256:...`,
+/// without consulting the index.  The flatpak ref is synthesized from the metadata in the image.
+pub async fn install_oci<ObjectID: FsVerityHashValue>(
+    repo: &Arc<Repository<ObjectID>>,
+    img_ref: &str,
+    arch: &str,
+    branch: &str,
+    options: &InstallOptions,
+    progress: &impl Fn(Progress),
+) -> Result<Ref> {
+    let _lock = RepoLock::shared(repo)?;
+    println!(">>> Reading flatpak metadata from {img_ref}");
+
+    // We don't know the ref yet, so we can't name the stream
+    let (digest, verity) = with_progress(
+        repo,
+        None,
+        progress,
+        composefs_oci::pull(repo, img_ref, None),
+    )
+    .await?;
+    let filesystem =
+        composefs_oci::image::create_filesystem(repo, &hex::encode(digest), Some(&verity))?;
+    let manifest = Manifest::from_filesystem(repo, &filesystem)?;
+    let r#ref = manifest.get_ref(arch, branch)?;
+
+    // Now that we know the name, do the usual install.  Everything is already downloaded.  If the
+    // image was given by digest we can record it, otherwise we don't know what we installed.
+    let digest = img_ref
+        .rsplit_once('@')
+        .map(|(_, digest)| digest)
+        .filter(|digest| digest.starts_with("sha256:"));
+    install_one(repo, &r#ref, img_ref, digest, options).await?;
+
+    if r#ref.is_app() {
+        let runtime = manifest.get_runtime()?;
+        if !is_installed(repo, &runtime)? {
+            println!("Required runtime {runtime} is not installed");
+        }
+    }
+
+    Ok(r#ref)
+}
+
+/// Removes an installed ref, returning false if it wasn't installed.  Refuses to remove runtimes
+/// that are still used by installed apps, unless `force` is set.
+pub(crate) fn uninstall<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+    force: bool,
+) -> Result<bool> {
+    if r#ref.is_runtime() && !force {
+        for app in installed_refs(repo)?.iter().filter(|r| r.is_app()) {
+            let Some(manifest) = installed_manifest(repo, app)? else {
+                continue;
+            };
+            if manifest.get_runtime()? == *r#ref {
+                bail!("{ref} is used by {app}: uninstall that first, or use --force");
+            }
+        }
+    }
+
+    // The image tells us which files we exported, so read it while it's still there
+    let filesystem = if r#ref.is_app() {
+        installed_filesystem(repo, r#ref)?
+    } else {
+        None
     };
 
-    Ok((app, runtime))
+    match unlinkat(
+        repo.objects_dir()?,
+        format!("../streams/refs/flatpak-rs/{ref}"),
+        AtFlags::empty(),
+    ) {
+        Ok(()) => {}
+        Err(Errno::NOENT) => return Ok(false),
+        Err(err) => Err(err).with_context(|| format!("Unable to remove stream ref for {ref}"))?,
+    }
+
+    record_digest(repo, r#ref, None)?;
+    unpin(repo, r#ref)?;
+
+    if let Some(filesystem) = filesystem {
+        if let Err(err) = remove_desktop_files(r#ref, &filesystem) {
+            warning(format!("Unable to remove desktop files of {ref}: {err:#}"));
+        }
+    }
+    if let Err(err) = remove_ld_cache(repo, r#ref) {
+        warning(format!(
+            "Unable to remove the ld.so.cache of {ref}: {err:#}"
+        ));
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn pull_tracking() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let repo = tmp.path();
+        fs::create_dir_all(repo.join("objects/ab"))?;
+        fs::create_dir_all(repo.join("streams/refs"))?;
+        fs::write(repo.join("objects/ab/old"), [0; 100])?;
+        fs::write(repo.join("streams/old"), "")?;
+
+        let objects_dir = fs::File::open(repo.join("objects"))?;
+        let mut tracker = PullTracker::new(objects_dir.into())?;
+        assert_eq!((tracker.written, tracker.layers), (0, 0));
+
+        fs::create_dir(repo.join("objects/cd"))?;
+        fs::write(repo.join("objects/cd/new"), [0; 10])?;
+        fs::write(repo.join("objects/ab/new"), [0; 20])?;
+        fs::write(repo.join("streams/layer"), "")?;
+        tracker.scan()?;
+        assert_eq!((tracker.written, tracker.layers), (30, 1));
+
+        // Nothing gets counted twice
+        tracker.scan()?;
+        assert_eq!((tracker.written, tracker.layers), (30, 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn repo_lock() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        fs::create_dir(tmp.path().join("objects"))?;
+        let objects_dir = fs::File::open(tmp.path().join("objects"))?.into();
+        let lock = |operation| RepoLock::try_lock(&objects_dir, operation).map(|l| l.is_some());
+
+        let shared = RepoLock::try_lock(&objects_dir, FlockOperation::NonBlockingLockShared)?;
+        assert!(shared.is_some());
+        assert!(lock(FlockOperation::NonBlockingLockShared)?);
+        assert!(!lock(FlockOperation::NonBlockingLockExclusive)?);
+
+        drop(shared);
+        let exclusive = RepoLock::try_lock(&objects_dir, FlockOperation::NonBlockingLockExclusive)?;
+        assert!(exclusive.is_some());
+        assert!(!lock(FlockOperation::NonBlockingLockShared)?);
+
+        Ok(())
+    }
 }
@@ -1,20 +1,200 @@
-use std::process;
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
+};
 
+use anyhow::{Context, Result, bail};
+use rustix::{
+    io::Errno,
+    process::{Pid, test_kill_process},
+    rand::{GetRandomFlags, getrandom},
+};
+
+use crate::{manifest::Manifest, r#ref::Ref};
+
+/// A running sandbox, tracked in `$XDG_RUNTIME_DIR/.flatpak/{id}` on the host.  The directory
+/// contains the `pid` of the process that runs the sandbox, and its `info`, in the same format as
+/// `/.flatpak-info` in the sandbox.  Without XDG_RUNTIME_DIR, instances are not tracked.
 #[derive(Debug)]
 pub(crate) struct Instance {
     id: String,
+    /// The directory and the `info` file in it.  The file is opened up front: we might not be
+    /// allowed to create files on the host once we're sandboxed.
+    tracked: Option<(PathBuf, File)>,
 }
 
 impl Instance {
-    /// Create an instance ID based on the PID of the caller.
-    /// TODO: we probably want something better...
-    pub(crate) fn new_pid() -> Self {
-        Self {
-            id: format!("{}", process::id()),
+    /// The directory where running instances are tracked: `$XDG_RUNTIME_DIR/.flatpak`.
+    fn instances_dir() -> Result<PathBuf> {
+        let runtime_dir = dirs::runtime_dir().context("We require XDG_RUNTIME_DIR set")?;
+        Ok(runtime_dir.join(".flatpak"))
+    }
+
+    fn random_id() -> Result<String> {
+        let mut bytes = [0u8; 4];
+        getrandom(&mut bytes, GetRandomFlags::empty()).context("Unable to get random bytes")?;
+        Ok(u32::from_ne_bytes(bytes).to_string())
+    }
+
+    /// Allocates a new instance ID.  IDs are random, and reserved by creating a directory for
+    /// them in `$XDG_RUNTIME_DIR/.flatpak`, so they won't collide with those of other instances,
+    /// even from other processes.
+    pub(crate) fn new() -> Result<Self> {
+        if dirs::runtime_dir().is_none() {
+            log::debug!("XDG_RUNTIME_DIR isn't set, so the instance won't be tracked");
+            return Self::untracked();
         }
+
+        let instances_dir = Self::instances_dir()?;
+        fs::create_dir_all(&instances_dir)
+            .with_context(|| format!("Unable to create {instances_dir:?}"))?;
+
+        let (id, dir) = loop {
+            let id = Self::random_id()?;
+            let dir = instances_dir.join(&id);
+
+            match fs::create_dir(&dir) {
+                Ok(()) => break (id, dir),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+                Err(err) => Err(err).context("Unable to reserve instance ID")?,
+            }
+        };
+
+        let write_pid = || writeln!(File::create(dir.join("pid"))?, "{}", process::id());
+        let info = write_pid()
+            .and_then(|()| File::create(dir.join("info")))
+            .with_context(|| format!("Unable to populate {dir:?}"))?;
+
+        Ok(Self {
+            id,
+            tracked: Some((dir, info)),
+        })
+    }
+
+    /// An instance that isn't tracked, with an ID that might not be unique.
+    pub(crate) fn untracked() -> Result<Self> {
+        Ok(Self {
+            id: Self::random_id()?,
+            tracked: None,
+        })
     }
 
     pub(crate) fn get_id(&self) -> &str {
         &self.id
     }
+
+    /// The directory of the instance on the host, if it's tracked.
+    pub(crate) fn get_dir(&self) -> Option<&Path> {
+        self.tracked.as_ref().map(|(dir, _)| dir.as_path())
+    }
+
+    /// The `info` file of the instance, to be written once we know what's in the sandbox.
+    pub(crate) fn info_file(&self) -> Option<&File> {
+        self.tracked.as_ref().map(|(_, info)| info)
+    }
+
+    /// Removes the directory of the instance, once the sandbox is gone.
+    pub(crate) fn remove(&self) {
+        let Some((dir, _)) = &self.tracked else {
+            return;
+        };
+        if let Err(err) = fs::remove_dir_all(dir) {
+            if err.kind() != ErrorKind::NotFound {
+                log::warn!("Unable to remove instance directory {dir:?}: {err}");
+            }
+        }
+    }
+}
+
+/// What we know about a running instance from the outside, for listing them.
+#[derive(Debug)]
+pub(crate) struct RunningInstance {
+    pub(crate) id: String,
+    pub(crate) pid: u32,
+    pub(crate) r#ref: Ref,
+    /// The contents of the `info` file
+    pub(crate) info: Manifest,
+    /// When the `pid` file was written
+    pub(crate) started: SystemTime,
+}
+
+impl RunningInstance {
+    /// Reads an instance directory.  Returns None if the process is gone, or if the sandbox
+    /// hasn't gotten around to writing its info yet.
+    fn load(id: String, dir: &Path) -> Result<Option<Self>> {
+        let pid_file = dir.join("pid");
+        let pid: u32 = match fs::read_to_string(&pid_file) {
+            Ok(pid) => pid.trim().parse().context("Invalid pid file")?,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => Err(err).context("Unable to read pid file")?,
+        };
+        let started = fs::metadata(&pid_file)?.modified()?;
+
+        let alive = Pid::from_raw(pid as i32).is_some_and(|pid| {
+            // EPERM means that it exists, but belongs to someone else
+            !matches!(test_kill_process(pid), Err(Errno::SRCH))
+        });
+        if !alive {
+            return Ok(None);
+        }
+
+        let info = fs::read_to_string(dir.join("info")).context("Unable to read info file")?;
+        if info.is_empty() {
+            return Ok(None);
+        }
+        let manifest = Manifest::new(&info)?;
+        let r#ref = manifest.get_ref(
+            manifest.get_opt("Instance", "arch").unwrap_or_default(),
+            manifest.get_opt("Instance", "branch").unwrap_or_default(),
+        )?;
+
+        Ok(Some(Self {
+            id,
+            pid,
+            r#ref,
+            info: manifest,
+            started,
+        }))
+    }
+
+    /// Looks up a running instance by its ID.
+    pub(crate) fn get(id: &str) -> Result<Self> {
+        let dir = Instance::instances_dir()?.join(id);
+        if !dir.is_dir() {
+            bail!("No such instance {id}");
+        }
+        Self::load(id.to_string(), &dir)
+            .with_context(|| format!("Unable to read instance {id}"))?
+            .with_context(|| format!("Instance {id} is not running"))
+    }
+
+    /// Lists the instances that are still running, oldest first.  Directories left behind by
+    /// sandboxes that didn't get to clean up after themselves are skipped.
+    pub(crate) fn list() -> Result<Vec<Self>> {
+        let instances_dir = Instance::instances_dir()?;
+        let entries = match fs::read_dir(&instances_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => Err(err).with_context(|| format!("Unable to read {instances_dir:?}"))?,
+        };
+
+        let mut instances = vec![];
+        for entry in entries {
+            let entry = entry?;
+            let Ok(id) = entry.file_name().into_string() else {
+                continue;
+            };
+            match Self::load(id, &entry.path()) {
+                Ok(Some(instance)) => instances.push(instance),
+                Ok(None) => {}
+                Err(err) => log::debug!("Skipping instance {:?}: {err:#}", entry.path()),
+            }
+        }
+
+        instances.sort_by_key(|instance| instance.started);
+        Ok(instances)
+    }
 }
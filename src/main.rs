@@ -1,16 +1,40 @@
+mod auth;
+mod export;
+mod flatpakref;
 mod index;
 mod install;
 mod instance;
 mod manifest;
+mod output;
+mod pin;
+mod prune;
 mod r#ref;
 mod sandbox;
+mod settings;
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::{index::get_index, r#ref::Ref, sandbox::run_sandboxed};
+use crate::{
+    index::{IndexEntry, IndexOptions, IndexSource, format_size, get_index},
+    install::{InstallOptions, Progress},
+    instance::RunningInstance,
+    manifest::Manifest,
+    output::{ColorChoice, OutputFormat, format_ref, label},
+    r#ref::Ref,
+    sandbox::{RunOptions, enter_instance, run_sandboxed},
+    settings::Settings,
+};
 use anyhow::{Context, Result, bail};
-use clap::{Parser, Subcommand};
-use composefs::fsverity::Sha256HashValue;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use composefs::{fsverity::Sha256HashValue, repository::Repository};
 
 #[derive(Parser)]
 #[command(
@@ -21,88 +45,656 @@ use composefs::fsverity::Sha256HashValue;
 struct Args {
     #[clap(long, default_value = "https://registry.fedoraproject.org/")]
     repository: String,
+    #[clap(long, default_value = std::env::consts::ARCH, help = "Architecture to query")]
+    arch: String,
+    #[clap(long, help = "Only use the cached index, don't access the network")]
+    offline: bool,
+    #[clap(
+        long,
+        conflicts_with = "offline",
+        help = "Ignore the cached index and fetch it again"
+    )]
+    refresh: bool,
+    #[clap(
+        long,
+        default_value_t = 3,
+        help = "Number of times to retry fetching the index after network or server errors"
+    )]
+    retries: u32,
+    #[clap(
+        long,
+        default_value_t = 60,
+        help = "Timeout in seconds for each attempt at fetching the index"
+    )]
+    timeout: u64,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Also trust the CA certificates in this PEM file for the index [default: $SSL_CERT_FILE]"
+    )]
+    ca_cert: Option<PathBuf>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "When to use colors in the output"
+    )]
+    color: ColorChoice,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "Output format for list, search and info"
+    )]
+    format: OutputFormat,
+    #[clap(
+        short,
+        long,
+        action = ArgAction::Count,
+        global = true,
+        help = "Log more (-v for info, -vv for debug, -vvv for trace), unless RUST_LOG is set"
+    )]
+    verbose: u8,
     #[command(subcommand)]
     command: Cmd,
 }
 
 #[derive(Subcommand)]
 enum Cmd {
-    List,
+    List {
+        #[clap(
+            long,
+            help = "List installed refs instead of the ones in the repository"
+        )]
+        installed: bool,
+    },
     Search {
         term: String,
+        #[clap(
+            long,
+            help = "Also match misspelled terms against the ref and name, best matches first"
+        )]
+        fuzzy: bool,
     },
     Info {
         r#ref: Ref,
+        #[clap(long, help = "Show everything needed to run the ref")]
+        dependencies: bool,
     },
     Install {
+        #[clap(required_unless_present_any = ["from", "oci", "oci_archive", "oci_layout"])]
+        r#ref: Option<Ref>,
+        #[clap(
+            long,
+            value_name = "FILE|URL",
+            conflicts_with_all = ["ref", "image"],
+            help = "Install what a .flatpakref file points at, from the registry it names"
+        )]
+        from: Option<String>,
+        #[clap(
+            long,
+            group = "image",
+            conflicts_with = "ref",
+            help = "Install directly from an image like docker://registry/name@sha256:..."
+        )]
+        oci: Option<String>,
+        #[clap(
+            long,
+            group = "image",
+            conflicts_with = "ref",
+            help = "Install from a local OCI image archive (tarball)"
+        )]
+        oci_archive: Option<PathBuf>,
+        #[clap(
+            long,
+            group = "image",
+            conflicts_with = "ref",
+            help = "Install from a local OCI image layout directory"
+        )]
+        oci_layout: Option<PathBuf>,
+        #[clap(
+            long,
+            requires = "image",
+            default_value = "master",
+            help = "Branch for refs installed with --oci, --oci-archive or --oci-layout"
+        )]
+        branch: String,
+        #[clap(
+            long,
+            conflicts_with = "image",
+            help = "Remove images superseded by this installation"
+        )]
+        prune_old: bool,
+        #[clap(long, help = "Download the image again even if it is up to date")]
+        reinstall: bool,
+        #[clap(long, help = "Check the fsverity digests of the installed objects")]
+        verify: bool,
+        #[clap(long, help = "Don't add the app to the menus of the desktop")]
+        no_desktop: bool,
+    },
+    Update {
+        #[clap(long, help = "Remove images superseded by the updates")]
+        prune_old: bool,
+        #[clap(long, help = "Check the fsverity digests of the updated objects")]
+        verify: bool,
+    },
+    Uninstall {
+        r#ref: Ref,
+        #[clap(long, help = "Remove runtimes even if installed apps still use them")]
+        force: bool,
+    },
+    Pin {
+        r#ref: Ref,
+    },
+    Unpin {
         r#ref: Ref,
     },
+    Prune {
+        #[clap(long, help = "Only report what would be removed")]
+        dry_run: bool,
+        #[clap(
+            long,
+            help = "Also uninstall runtimes that no installed app uses (unless they're pinned)"
+        )]
+        unused_runtimes: bool,
+    },
     Run {
         r#ref: Ref,
         #[clap(long, help = "Command to run instead of default")]
         command: Option<String>,
+        #[command(flatten)]
+        options: Box<RunOptions>,
         args: Vec<String>,
     },
+    Ps,
+    Enter {
+        instance: String,
+        #[clap(last = true, help = "Command to run instead of /bin/sh")]
+        command: Vec<String>,
+    },
+    /// Print a completion script for the shell
+    #[command(hide = true)]
+    Completions {
+        shell: Shell,
+    },
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    env_logger::init();
+fn installed_str(installed: bool) -> &'static str {
+    if installed {
+        "installed"
+    } else {
+        "not installed"
+    }
+}
+
+fn size_str(size: Option<u64>) -> String {
+    size.map_or_else(|| "unknown".to_string(), format_size)
+}
+
+/// Turns a local OCI archive or layout into an image reference for the given transport.  The
+/// path is made absolute, since it's resolved by another process.
+fn local_image(transport: &str, path: &Path) -> Result<String> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Unable to find {}", path.display()))?;
+    Ok(format!("{transport}:{}", path.display()))
+}
+
+/// Renders download progress on stderr: a line for each completed layer, and one at the end.  If
+/// stderr is a terminal and we can use escape sequences, a line in between is updated in place.
+fn show_progress(progress: Progress) {
+    let in_place = output::color_enabled() && std::io::stderr().is_terminal();
+    let clear = if in_place { "\r\x1b[K" } else { "" };
+    match progress {
+        Progress::Downloading { .. } if !in_place => {}
+        Progress::Downloading {
+            written,
+            expected: Some(expected),
+        } => {
+            let percent = (written * 100 / expected.max(1)).min(100);
+            eprint!(
+                "{clear}{} of {} ({percent}%)",
+                format_size(written),
+                format_size(expected)
+            );
+        }
+        Progress::Downloading {
+            written,
+            expected: None,
+        } => eprint!("{clear}{}", format_size(written)),
+        Progress::Layer { layers, written } => {
+            eprintln!("{clear}Layer {layers} done, wrote {}", format_size(written))
+        }
+        Progress::Finished { written } => eprintln!("{clear}Wrote {}", format_size(written)),
+    }
+}
+
+impl IndexSource for Args {
+    async fn get_index(&self) -> Result<HashMap<Ref, IndexEntry>> {
+        let ssl_cert_file = std::env::var_os("SSL_CERT_FILE").map(PathBuf::from);
+        let options = IndexOptions {
+            arch: &self.arch,
+            offline: self.offline,
+            refresh: self.refresh,
+            retries: self.retries,
+            timeout: Duration::from_secs(self.timeout),
+            ca_cert: self.ca_cert.as_deref().or(ssl_cert_file.as_deref()),
+        };
+
+        get_index(&self.repository, &options)
+            .await
+            .with_context(|| format!("Fetching index from {}", self.repository))
+    }
+}
 
-    let args = Args::parse();
+type Repo = Repository<Sha256HashValue>;
 
-    let repo = Arc::new(composefs::repository::Repository::<Sha256HashValue>::open_user()?);
+/// Runs the command.  The index comes from `source`, and the repository from `open_repo`, which
+/// only gets called by the commands that need it.
+async fn run(
+    args: &Args,
+    source: &impl IndexSource,
+    open_repo: impl Fn() -> Result<Repo>,
+) -> Result<()> {
     match &args.command {
-        Cmd::List => {
-            let index = get_index(&args.repository)
-                .await
-                .with_context(|| format!("Fetching index from {}", args.repository))?;
+        Cmd::List { installed } => {
+            let refs = if *installed {
+                install::installed_refs(&open_repo()?)?
+            } else {
+                source.get_index().await?.into_keys().collect()
+            };
 
-            for r#ref in index.keys() {
-                println!("{ref}");
+            if args.format == OutputFormat::Json {
+                println!("{}", serde_json::to_string_pretty(&refs)?);
+            } else {
+                for r#ref in &refs {
+                    println!("{}", format_ref(r#ref));
+                }
             }
         }
-        Cmd::Search { term } => {
-            let index = get_index(&args.repository)
-                .await
-                .with_context(|| format!("Fetching index from {}", args.repository))?;
+        Cmd::Search { term, fuzzy } => {
+            let index = source.get_index().await?;
 
             let term = term.to_lowercase();
+            let results: Vec<_> = if *fuzzy {
+                let mut scored = index
+                    .iter()
+                    .filter_map(|(r#ref, entry)| {
+                        Some((entry.fuzzy_score(r#ref, &term)?, r#ref, entry))
+                    })
+                    .collect::<Vec<_>>();
+                scored.sort_by(|(a, a_ref, _), (b, b_ref, _)| {
+                    b.cmp(a).then_with(|| a_ref.as_ref().cmp(b_ref.as_ref()))
+                });
+                scored
+                    .into_iter()
+                    .map(|(_, r#ref, entry)| (r#ref, entry))
+                    .collect()
+            } else {
+                index
+                    .iter()
+                    .filter(|(r#ref, entry)| {
+                        r#ref.as_ref().to_lowercase().contains(&term) || entry.matches(&term)
+                    })
+                    .collect()
+            };
 
-            for r#ref in index.keys() {
-                if r#ref.as_ref().to_lowercase().contains(&term) {
-                    println!("{ref}");
+            if args.format == OutputFormat::Json {
+                let refs = results.iter().map(|(r#ref, _)| r#ref).collect::<Vec<_>>();
+                println!("{}", serde_json::to_string_pretty(&refs)?);
+            } else {
+                for (r#ref, entry) in results {
+                    if let Some(summary) = &entry.summary {
+                        println!("{} - {summary}", format_ref(r#ref));
+                    } else {
+                        println!("{}", format_ref(r#ref));
+                    }
                 }
             }
         }
-        Cmd::Info { r#ref } => {
-            let index = get_index(&args.repository)
-                .await
-                .with_context(|| format!("Fetching index from {}", args.repository))?;
+        Cmd::Info {
+            r#ref,
+            dependencies,
+        } => {
+            let index = source.get_index().await?;
 
-            let Some((img, manifest)) = index.get(r#ref) else {
+            let Some(entry) = index.get(r#ref) else {
                 bail!("No such ref {ref}");
             };
+            let dependencies = dependencies
+                .then(|| install::find_dependencies(&open_repo()?, &index, r#ref))
+                .transpose()?;
 
-            println!("{}{}", &args.repository, &img);
-            println!("{manifest:?}");
+            if args.format == OutputFormat::Json {
+                let mut info = serde_json::json!({
+                    "ref": r#ref,
+                    "entry": entry,
+                    "manifest": Manifest::new(&entry.metadata)?,
+                });
+                if let Some(dependencies) = &dependencies {
+                    info["dependencies"] = serde_json::to_value(dependencies)?;
+                }
+                println!("{}", serde_json::to_string_pretty(&info)?);
+                return Ok(());
+            }
+
+            let manifest = Manifest::new(&entry.metadata)?;
+            println!("{} {}", label("ID"), r#ref.get_id());
+            if let Some(name) = &entry.name {
+                println!("{} {name}", label("Name"));
+            }
+            if let Some(summary) = &entry.summary {
+                println!("{} {summary}", label("Summary"));
+            }
+            println!("{} {}{}", label("Image"), &args.repository, &entry.image);
+            println!("{} {}", label("Digest"), entry.digest);
+            if r#ref.is_app() {
+                println!(
+                    "{} {}",
+                    label("Runtime"),
+                    format_ref(&manifest.get_runtime()?)
+                );
+            }
+            if let Some(version) = manifest.get_runtime_version() {
+                println!("{} {version}", label("Runtime version"));
+            }
+            if let Some(command) = manifest.get_command() {
+                println!("{} {command}", label("Command"));
+            }
+            println!(
+                "{} {} / {} {}",
+                label("Download"),
+                size_str(entry.download_size),
+                label("Installed"),
+                size_str(entry.installed_size)
+            );
+
+            let context = manifest.get_context();
+            for (name, values) in [
+                ("Shared", &context.shared),
+                ("Sockets", &context.sockets),
+                ("Filesystems", &context.filesystems),
+                ("Devices", &context.devices),
+                ("Persist", &context.persist),
+            ] {
+                if !values.is_empty() {
+                    println!("{} {}", label(name), values.join(", "));
+                }
+            }
+
+            let extensions = manifest.get_extensions();
+            if !extensions.is_empty() {
+                println!("{}", label("Extensions"));
+                for extension in &extensions {
+                    print!("  {}", extension.name);
+                    if let Some(directory) = &extension.directory {
+                        print!(" at {directory}");
+                    }
+                    if let Some(version) = &extension.version {
+                        print!(" version {version}");
+                    }
+                    if extension.subdirectories {
+                        print!(" (subdirectories)");
+                    }
+                    println!();
+                }
+            }
+
+            if let Some(dependencies) = dependencies {
+                println!("{}", label("Dependencies"));
+                for dependency in &dependencies {
+                    let installed = installed_str(dependency.installed);
+                    match dependency.kind {
+                        "runtime" => println!("  {} ({installed})", format_ref(&dependency.r#ref)),
+                        kind => {
+                            println!("  {} ({kind}, {installed})", format_ref(&dependency.r#ref))
+                        }
+                    }
+                }
+            }
         }
-        Cmd::Install { r#ref } => {
-            let index = get_index(&args.repository)
-                .await
-                .with_context(|| format!("Fetching index from {}", args.repository))?;
+        Cmd::Install {
+            r#ref,
+            from: _,
+            oci,
+            oci_archive,
+            oci_layout,
+            branch,
+            prune_old,
+            reinstall,
+            verify,
+            no_desktop,
+        } => {
+            let options = InstallOptions {
+                prune_old: *prune_old,
+                reinstall: *reinstall,
+                verify: *verify,
+                no_desktop: *no_desktop,
+            };
+            let image = match (oci, oci_archive, oci_layout) {
+                (Some(oci), ..) => Some(oci.clone()),
+                (_, Some(path), _) => Some(local_image("oci-archive", path)?),
+                (.., Some(path)) => Some(local_image("oci", path)?),
+                _ => None,
+            };
 
-            install::install(&repo, &args.repository, &index, r#ref).await?;
+            let repo = Arc::new(open_repo()?);
+            let r#ref = if let Some(image) = image {
+                install::install_oci(&repo, &image, &args.arch, branch, &options, &show_progress)
+                    .await?
+            } else {
+                // SAFETY: clap ensures that we have a ref (or a flatpakref that it came from) if
+                // we don't have an image
+                let r#ref = r#ref.as_ref().unwrap();
+                let index = source.get_index().await?;
+                install::install(
+                    &repo,
+                    &args.repository,
+                    &index,
+                    r#ref,
+                    &options,
+                    &show_progress,
+                )
+                .await?;
+                r#ref.clone()
+            };
             println!("Now: run {ref}");
         }
+        Cmd::Update { prune_old, verify } => {
+            let options = InstallOptions {
+                prune_old: *prune_old,
+                verify: *verify,
+                ..Default::default()
+            };
+            let index = source.get_index().await?;
+            install::update(
+                &Arc::new(open_repo()?),
+                &args.repository,
+                &index,
+                &options,
+                &show_progress,
+            )
+            .await?;
+        }
+        Cmd::Uninstall { r#ref, force } => {
+            if install::uninstall(&open_repo()?, r#ref, *force)? {
+                println!("Uninstalled {ref}");
+            } else {
+                println!("{ref} is not installed");
+            }
+        }
+        Cmd::Pin { r#ref } => {
+            if pin::pin(&open_repo()?, r#ref)? {
+                println!("Pinned {ref}");
+            } else {
+                println!("{ref} is already pinned");
+            }
+        }
+        Cmd::Unpin { r#ref } => {
+            if pin::unpin(&open_repo()?, r#ref)? {
+                println!("Unpinned {ref}");
+            } else {
+                println!("{ref} is not pinned");
+            }
+        }
+        Cmd::Prune {
+            dry_run,
+            unused_runtimes,
+        } => {
+            prune::prune(&open_repo()?, *unused_runtimes, *dry_run)?;
+        }
         Cmd::Run {
             r#ref,
             command,
+            options,
             args,
-        } => {
-            run_sandboxed(&repo, r#ref, command.as_deref(), args);
+        } => match run_sandboxed(
+            &Arc::new(open_repo()?),
+            r#ref,
+            command.as_deref(),
+            args,
+            options,
+        )? {},
+        Cmd::Ps => {
+            let instances = RunningInstance::list()?;
+            if !instances.is_empty() {
+                println!("{:<10} {:<8} {:<29} Ref", "Instance", "PID", "Started");
+            }
+            for instance in &instances {
+                println!(
+                    "{:<10} {:<8} {:<29} {}",
+                    instance.id,
+                    instance.pid,
+                    httpdate::fmt_http_date(instance.started),
+                    format_ref(&instance.r#ref)
+                );
+            }
+        }
+        Cmd::Enter { instance, command } => {
+            std::process::exit(enter_instance(instance, command)?);
+        }
+        Cmd::Completions { shell } => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(*shell, &mut command, name, &mut std::io::stdout());
         }
     }
 
     Ok(())
 }
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+
+    let level = match args.verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level)).init();
+    output::init(args.color);
+
+    match Settings::load() {
+        Ok(settings) => settings.apply(&matches, &mut args),
+        Err(err) => {
+            output::error(&err);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let Err(err) = flatpakref::apply(&mut args).await {
+        output::error(&err);
+        return ExitCode::FAILURE;
+    }
+
+    match run(&args, &args, Repo::open_user).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            output::error(&err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP: &str = "app/org.example.App/x86_64/stable";
+    const RUNTIME: &str = "runtime/org.example.Platform/x86_64/24.08";
+
+    fn entry(metadata: &str, name: &str) -> IndexEntry {
+        IndexEntry {
+            image: format!("{name}@sha256:0"),
+            digest: "sha256:0".to_string(),
+            metadata: metadata.to_string(),
+            download_size: None,
+            installed_size: None,
+            name: Some(name.to_string()),
+            summary: None,
+        }
+    }
+
+    /// Runs a commandline against a fixed index, without a repository.
+    fn run_with_index(commandline: &[&str]) -> Result<()> {
+        let index = HashMap::from([
+            (
+                APP.parse()?,
+                entry(
+                    "[Application]\nname=org.example.App\nruntime=org.example.Platform/x86_64/24.08\n",
+                    "Example",
+                ),
+            ),
+            (
+                RUNTIME.parse()?,
+                entry("[Runtime]\nname=org.example.Platform\n", "Platform"),
+            ),
+        ]);
+        let args = Args::try_parse_from([&["flatpak-next"], commandline].concat())?;
+        futures::executor::block_on(run(&args, &index, || bail!("No repository in tests")))
+    }
+
+    #[test]
+    fn index_commands_without_repository() {
+        run_with_index(&["list"]).unwrap();
+        run_with_index(&["--format=json", "search", "example"]).unwrap();
+        run_with_index(&["search", "--fuzzy", "exmaple"]).unwrap();
+        run_with_index(&["info", APP]).unwrap();
+        run_with_index(&["--format=json", "info", RUNTIME]).unwrap();
+    }
+
+    #[test]
+    fn info_of_unknown_ref() {
+        let err = run_with_index(&["info", "app/org.example.Missing/x86_64/stable"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No such ref app/org.example.Missing/x86_64/stable"
+        );
+    }
+
+    #[test]
+    fn repository_only_when_needed() {
+        for commandline in [
+            &["list", "--installed"][..],
+            &["info", "--dependencies", APP],
+            &["uninstall", APP],
+        ] {
+            let err = run_with_index(commandline).unwrap_err();
+            assert_eq!(err.to_string(), "No repository in tests");
+        }
+    }
+
+    #[test]
+    fn prune_old_needs_a_ref() {
+        let parse =
+            |commandline: &[&str]| Args::try_parse_from([&["flatpak-next"], commandline].concat());
+        assert!(parse(&["install", "--prune-old", APP]).is_ok());
+        let err = parse(&["install", "--prune-old", "--oci", "docker://example/app"])
+            .map(drop)
+            .unwrap_err();
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+}
@@ -1,42 +1,324 @@
-use anyhow::{Context, Result};
+use std::{fs::File, io::Read};
+
+use anyhow::{Context as _, Result, bail};
+use composefs::{
+    fsverity::FsVerityHashValue,
+    repository::Repository,
+    tree::{FileSystem, RegularFile},
+};
 use ini::{Ini, Properties};
+use serde::{Serialize, Serializer, ser::SerializeMap};
 
 use crate::r#ref::Ref;
 
+/// The permissions from the `[Context]` section of a manifest.
+#[derive(Debug, Default)]
+pub(crate) struct Context {
+    /// Subsystems shared with the host, like `network` or `ipc`
+    pub(crate) shared: Vec<String>,
+    /// Sockets exposed to the app, like `wayland` or `pulseaudio`
+    pub(crate) sockets: Vec<String>,
+    /// Host filesystems exposed to the app, like `home` or `xdg-download:ro`
+    pub(crate) filesystems: Vec<String>,
+    /// Devices exposed to the app, like `dri` or `all`
+    pub(crate) devices: Vec<String>,
+    /// Directories in the home directory that are kept between runs, like `.mozilla`
+    pub(crate) persist: Vec<String>,
+}
+
+/// An extension point declared in an `[Extension name]` section of a manifest.
+#[derive(Debug)]
+pub(crate) struct Extension {
+    /// The name of the extension, like `org.freedesktop.Platform.GL`
+    pub(crate) name: String,
+    /// Where the extension gets mounted, relative to `/usr` or `/app`
+    pub(crate) directory: Option<String>,
+    pub(crate) version: Option<String>,
+    /// If there can be several extensions, mounted in subdirectories of `directory`
+    pub(crate) subdirectories: bool,
+    /// If the extension gets installed along with the ref, unless it says `no-autodownload`
+    pub(crate) autodownload: bool,
+}
+
 // don't store indexes: scanning for the correct parts is fast enough...
 #[derive(Debug)]
 pub(crate) struct Manifest {
     ini: Ini,
+    /// If this is an `[Application]`, as opposed to a `[Runtime]`
+    is_app: bool,
+    /// The ID of the app or runtime, like `org.gnome.Calculator`
+    name: String,
+    /// The runtime that an app runs on.  Always present for apps, never for runtimes.
+    runtime: Option<Ref>,
+    /// The SDK used to build an app, or that goes with a runtime
+    sdk: Option<Ref>,
+    /// The command that runs an app
+    command: Option<String>,
+    /// The `runtime-version=` key, which some manifests carry next to the runtime ref
+    runtime_version: Option<String>,
+}
+
+struct SerializeSection<'a>(&'a Properties);
+
+impl Serialize for SerializeSection<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(self.0.iter())
+    }
+}
+
+/// Serializes as the sections of the keyfile, each as a map of keys to values.
+impl Serialize for Manifest {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        for (section, properties) in self.ini.iter() {
+            if let Some(section) = section {
+                map.serialize_entry(section, &SerializeSection(properties))?;
+            }
+        }
+        map.end()
+    }
 }
 
 impl Manifest {
     pub fn new(s: impl AsRef<str>) -> Result<Self> {
         let ini = Ini::load_from_str(s.as_ref()).context("Failed to parse flatpak manifest")?;
-        Ok(Self { ini })
-    }
 
-    fn section(&self, name: &str) -> Result<&Properties> {
-        self.ini
-            .section(Some(name))
-            .with_context(|| format!("Manifest is missing section [{name}]"))
+        let (is_app, section_name) = if ini.section(Some("Application")).is_some() {
+            (true, "Application")
+        } else if ini.section(Some("Runtime")).is_some() {
+            (false, "Runtime")
+        } else {
+            bail!("Manifest has neither an [Application] nor a [Runtime] section");
+        };
+
+        // SAFETY: we just checked that the section exists
+        let section = ini.section(Some(section_name)).unwrap();
+        let get_opt = |key| section.get(key).map(str::to_string);
+        let get = |key| {
+            section
+                .get(key)
+                .with_context(|| format!("Section [{section_name}] is missing {key}="))
+        };
+
+        let name = get("name")?.to_string();
+        let runtime = if is_app {
+            Some(Ref::new_runtime(get("runtime")?).context("Invalid runtime= in manifest")?)
+        } else {
+            None
+        };
+        let sdk = section
+            .get("sdk")
+            .map(Ref::new_runtime)
+            .transpose()
+            .context("Invalid sdk= in manifest")?;
+        let command = get_opt("command");
+        let runtime_version = get_opt("runtime-version");
+
+        Ok(Self {
+            ini,
+            is_app,
+            name,
+            runtime,
+            sdk,
+            command,
+            runtime_version,
+        })
     }
 
-    pub(crate) fn get(&self, section: &str, key: &str) -> Result<&str> {
-        self.section(section)?
-            .get(key)
-            .with_context(|| format!("Section [{section}] is missing {key}="))
+    /// Reads the manifest from the `metadata` file at the root of a flatpak image.
+    pub(crate) fn from_filesystem<ObjectID: FsVerityHashValue>(
+        repo: &Repository<ObjectID>,
+        filesystem: &FileSystem<ObjectID>,
+    ) -> Result<Self> {
+        let data = match filesystem.root.get_file("metadata".as_ref())? {
+            RegularFile::Inline(data) => data.clone().into_vec(),
+            RegularFile::External(id, ..) => {
+                let mut data = vec![];
+                File::from(repo.open_object(id)?).read_to_end(&mut data)?;
+                data
+            }
+        };
+
+        Self::new(std::str::from_utf8(&data).context("Flatpak manifest is not valid utf-8")?)
     }
 
-    #[allow(dead_code)]
+    /// Looks up a field that doesn't have its own accessor.
     pub(crate) fn get_opt(&self, section: &str, key: &str) -> Option<&str> {
         self.ini.section(Some(section))?.get(key)
     }
 
+    /// Synthesizes a ref for the app or runtime described by this manifest.  The metadata doesn't
+    /// include the architecture or branch, so those need to come from elsewhere.
+    pub(crate) fn get_ref(&self, arch: &str, branch: &str) -> Result<Ref> {
+        let kind = if self.is_app { "app" } else { "runtime" };
+        format!("{kind}/{}/{arch}/{branch}", self.name).try_into()
+    }
+
+    /// The command that runs the app, if it declares one.
+    pub(crate) fn get_command(&self) -> Option<&str> {
+        self.command.as_deref()
+    }
+
+    /// The runtime that the app runs on.
     pub(crate) fn get_runtime(&self) -> Result<Ref> {
-        Ref::new_runtime(self.get("Application", "runtime")?)
+        self.runtime
+            .clone()
+            .with_context(|| format!("{} is a runtime, not an app", self.name))
+    }
+
+    /// The SDK that goes with the app or runtime, if any.
+    pub(crate) fn get_sdk(&self) -> Option<&Ref> {
+        self.sdk.as_ref()
+    }
+
+    /// The `runtime-version=` of the manifest, if it has one.
+    pub(crate) fn get_runtime_version(&self) -> Option<&str> {
+        self.runtime_version.as_deref()
+    }
+
+    /// Splits a list value like `network;ipc;` into its items.
+    pub(crate) fn get_list(&self, section: &str, key: &str) -> Vec<String> {
+        self.get_opt(section, key)
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Reads the permissions of the app.  A missing `[Context]` section means no permissions.
+    pub(crate) fn get_context(&self) -> Context {
+        Context {
+            shared: self.get_list("Context", "shared"),
+            sockets: self.get_list("Context", "sockets"),
+            filesystems: self.get_list("Context", "filesystems"),
+            devices: self.get_list("Context", "devices"),
+            persist: self.get_list("Context", "persist"),
+        }
+    }
+
+    /// Lists the extension points declared by the manifest.
+    pub(crate) fn get_extensions(&self) -> Vec<Extension> {
+        self.ini
+            .iter()
+            .filter_map(|(section, properties)| {
+                let name = section?.strip_prefix("Extension ")?;
+                Some(Extension {
+                    name: name.trim().to_string(),
+                    directory: properties.get("directory").map(str::to_string),
+                    version: properties.get("version").map(str::to_string),
+                    subdirectories: properties.get("subdirectories") == Some("true"),
+                    autodownload: properties.get("no-autodownload") != Some("true"),
+                })
+            })
+            .collect()
+    }
+
+    /// Lists the D-Bus names in a policy section like `[Session Bus Policy]`, along with what the
+    /// app may do with them: `see`, `talk` or `own`.
+    pub(crate) fn get_bus_policy(&self, section: &str) -> impl Iterator<Item = (&str, &str)> {
+        self.ini
+            .section(Some(section))
+            .into_iter()
+            .flat_map(Properties::iter)
+    }
+
+    /// Lists the environment variables to set.  Lots of runtimes don't have an `[Environment]`
+    /// section at all, which is the same as an empty one.
+    pub(crate) fn get_environment(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.ini
+            .section(Some("Environment"))
+            .into_iter()
+            .flat_map(Properties::iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_fields() {
+        let manifest = Manifest::new(concat!(
+            "[Application]\n",
+            "name=org.example.App\n",
+            "runtime=org.example.Platform/x86_64/48\n",
+            "runtime-version=48\n",
+            "command=example\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            manifest.get_runtime().unwrap().as_ref(),
+            "runtime/org.example.Platform/x86_64/48"
+        );
+        assert_eq!(manifest.get_runtime_version(), Some("48"));
+        assert_eq!(manifest.get_command(), Some("example"));
+        assert_eq!(manifest.get_sdk(), None);
+
+        let manifest = Manifest::new("[Runtime]\nname=org.example.Platform\n").unwrap();
+        assert_eq!(manifest.get_runtime_version(), None);
+    }
+
+    #[test]
+    fn environment_missing() {
+        let manifest = Manifest::new("[Runtime]\nname=org.example.Platform\n").unwrap();
+        assert_eq!(manifest.get_environment().count(), 0);
+    }
+
+    #[test]
+    fn environment_present() {
+        let manifest = Manifest::new(concat!(
+            "[Runtime]\n",
+            "name=org.example.Platform\n",
+            "\n",
+            "[Environment]\n",
+            "GI_TYPELIB_PATH=/app/lib/girepository-1.0\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            Vec::from_iter(manifest.get_environment()),
+            [("GI_TYPELIB_PATH", "/app/lib/girepository-1.0")]
+        );
     }
 
-    pub(crate) fn get_environment(&self) -> Result<impl IntoIterator<Item = (&str, &str)>> {
-        self.section("Environment")
+    #[test]
+    fn extensions_autodownload() {
+        let manifest = Manifest::new(concat!(
+            "[Runtime]\n",
+            "name=org.example.Platform\n",
+            "\n",
+            "[Extension org.example.Platform.GL]\n",
+            "directory=lib/GL\n",
+            "version=1.4\n",
+            "subdirectories=true\n",
+            "\n",
+            "[Extension org.example.Platform.Debug]\n",
+            "directory=lib/debug\n",
+            "no-autodownload=true\n",
+        ))
+        .unwrap();
+        let extensions = manifest.get_extensions();
+        let summary = Vec::from_iter(extensions.iter().map(|extension| {
+            (
+                extension.name.as_str(),
+                extension.version.as_deref(),
+                extension.subdirectories,
+                extension.autodownload,
+            )
+        }));
+        assert_eq!(
+            summary,
+            [
+                ("org.example.Platform.GL", Some("1.4"), true, true),
+                ("org.example.Platform.Debug", None, false, false),
+            ]
+        );
     }
 }
@@ -0,0 +1,85 @@
+use std::{
+    fmt::Display,
+    io::IsTerminal,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+
+use crate::r#ref::Ref;
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub(crate) enum ColorChoice {
+    /// use color if stdout is a terminal and NO_COLOR isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+// This is set once at startup, before anything gets printed.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const BOLD: &str = "1";
+const DIM: &str = "2";
+const RED: &str = "31";
+const YELLOW: &str = "33";
+
+pub(crate) fn init(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            // https://no-color.org/
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stdout().is_terminal()
+        }
+    };
+
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether colors (and other escape sequences) can be used in the output.
+pub(crate) fn color_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn paint(style: &str, text: impl Display) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{style}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Formats a ref, highlighting the ID.
+pub(crate) fn format_ref(r#ref: &Ref) -> String {
+    let (_, kind, id, arch, branch) = r#ref.get_parts();
+    format!(
+        "{}/{}/{}/{}",
+        paint(DIM, kind),
+        paint(BOLD, id),
+        arch,
+        branch
+    )
+}
+
+/// Formats a label for a "Label: value" line.
+pub(crate) fn label(text: &str) -> String {
+    paint(BOLD, format!("{text}:"))
+}
+
+pub(crate) fn warning(message: impl Display) {
+    eprintln!("{} {message}", paint(YELLOW, "Warning:"));
+}
+
+pub(crate) fn error(err: &anyhow::Error) {
+    eprintln!("{} {err:?}", paint(RED, "Error:"));
+}
@@ -0,0 +1,66 @@
+use anyhow::{Context, Result, bail};
+use composefs::{fsverity::FsVerityHashValue, repository::Repository};
+use rustix::{
+    fs::{AtFlags, Mode, OFlags, openat, unlinkat},
+    io::Errno,
+};
+
+use crate::{
+    install::{collect_refs, create_parents, is_installed, open_dir},
+    r#ref::Ref,
+};
+
+/// Pins are empty marker files in our own directory next to the repository, stored as
+/// kind/id/arch/branch like the stream refs.
+fn pin_path(r#ref: &Ref) -> String {
+    format!("../flatpak-rs/pins/{ref}")
+}
+
+/// Lists the pinned refs.
+pub(crate) fn pinned_refs<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+) -> Result<Vec<Ref>> {
+    let dirfd = match open_dir(repo.objects_dir()?, "../flatpak-rs/pins") {
+        Ok(dirfd) => dirfd,
+        Err(Errno::NOENT) => return Ok(vec![]),
+        Err(err) => Err(err).context("Unable to open pins directory")?,
+    };
+
+    let mut refs = vec![];
+    collect_refs(&dirfd, "", 0, &mut refs)?;
+    refs.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+    Ok(refs)
+}
+
+/// Pins an installed ref, so that prune never removes it.  Returns false if it was already pinned.
+pub(crate) fn pin<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<bool> {
+    if !is_installed(repo, r#ref)? {
+        bail!("{ref} is not installed");
+    }
+
+    let dirfd = repo.objects_dir()?;
+    let path = pin_path(r#ref);
+    create_parents(dirfd, &path)?;
+
+    let flags = OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::CLOEXEC;
+    match openat(dirfd, &path, flags, Mode::from(0o644)) {
+        Ok(_) => Ok(true),
+        Err(Errno::EXIST) => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("Unable to pin {ref}")),
+    }
+}
+
+/// Removes the pin of a ref.  Returns false if it wasn't pinned.
+pub(crate) fn unpin<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<bool> {
+    match unlinkat(repo.objects_dir()?, pin_path(r#ref), AtFlags::empty()) {
+        Ok(()) => Ok(true),
+        Err(Errno::NOENT) => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("Unable to unpin {ref}")),
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use composefs::{fsverity::FsVerityHashValue, repository::Repository};
+use rustix::{
+    fd::OwnedFd,
+    fs::{AtFlags, Dir, readlinkat, statat, unlinkat},
+    io::Errno,
+};
+
+use crate::{
+    index::format_size,
+    install::{
+        RepoLock, collect_objects, installed_filesystem, installed_manifest, installed_refs,
+        open_dir, read_stream_ref, uninstall,
+    },
+    output::format_ref,
+    pin::pinned_refs,
+    r#ref::Ref,
+};
+
+/// Lists the objects in the repository along with their sizes.  The objects are stored as
+/// `objects/ab/cdef...`, named after their fsverity digest.
+fn list_objects<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+) -> Result<HashMap<ObjectID, u64>> {
+    let objects_dir = repo.objects_dir()?;
+    let mut objects = HashMap::new();
+
+    for entry in Dir::read_from(objects_dir)? {
+        let entry = entry?;
+        let Ok(prefix) = entry.file_name().to_str() else {
+            continue;
+        };
+        if prefix.len() != 2 {
+            continue;
+        }
+
+        let subdir = open_dir(objects_dir, prefix)?;
+        for entry in Dir::read_from(&subdir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            let Ok(id) = ObjectID::from_hex(format!("{prefix}{name}")) else {
+                continue;
+            };
+
+            let stat = statat(&subdir, name, AtFlags::SYMLINK_NOFOLLOW)?;
+            objects.insert(id, stat.st_size as u64);
+        }
+    }
+
+    Ok(objects)
+}
+
+/// Parses a symlink target like `../objects/ab/cdef...` into an object ID.
+fn parse_object_link<ObjectID: FsVerityHashValue>(target: &str) -> Option<ObjectID> {
+    let (_, object) = target.split_once("objects/")?;
+    ObjectID::from_hex(object.replace('/', "")).ok()
+}
+
+/// Collects the objects pointed to by the symlinks in the top level of the `streams` or `images`
+/// directory of the repository.
+fn collect_named_objects<ObjectID: FsVerityHashValue>(
+    objects_dir: &OwnedFd,
+    dir: &str,
+    objects: &mut HashSet<ObjectID>,
+) -> Result<()> {
+    let dirfd = match open_dir(objects_dir, &format!("../{dir}")) {
+        Ok(dirfd) => dirfd,
+        Err(Errno::NOENT) => return Ok(()),
+        Err(err) => Err(err).with_context(|| format!("Unable to open {dir} directory"))?,
+    };
+
+    for entry in Dir::read_from(&dirfd)? {
+        let entry = entry?;
+        // Directories (like refs/) fail with EINVAL: the refs point at the named objects anyway
+        let Ok(target) = readlinkat(&dirfd, entry.file_name(), vec![]) else {
+            continue;
+        };
+        if let Some(id) = parse_object_link(&target.to_string_lossy()) {
+            objects.insert(id);
+        }
+    }
+
+    Ok(())
+}
+
+fn total_size<'a>(sizes: impl Iterator<Item = &'a u64>) -> (usize, u64) {
+    sizes.fold((0, 0), |(count, total), size| (count + 1, total + size))
+}
+
+/// Splits the installed refs into the ones to keep and the unused ones.  Apps and pinned refs are
+/// kept, along with the runtimes they need.  The other runtimes are unused.
+fn find_roots<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+) -> Result<(Vec<Ref>, Vec<Ref>)> {
+    let pinned = pinned_refs(repo)?;
+    let (mut roots, mut unused): (Vec<_>, Vec<_>) = installed_refs(repo)?
+        .into_iter()
+        .partition(|r#ref| r#ref.is_app() || pinned.contains(r#ref));
+
+    let mut runtimes = vec![];
+    for app in roots.iter().filter(|r#ref| r#ref.is_app()) {
+        if let Some(manifest) = installed_manifest(repo, app)? {
+            runtimes.push(manifest.get_runtime()?);
+        }
+    }
+    for runtime in runtimes {
+        if let Some(pos) = unused.iter().position(|r#ref| *r#ref == runtime) {
+            roots.push(unused.remove(pos));
+        }
+    }
+
+    Ok((roots, unused))
+}
+
+/// Removes the objects that aren't used by any installed ref anymore.  With `unused_runtimes`, it
+/// first uninstalls the runtimes that nothing needs.  Otherwise it only lists them: they might be
+/// SDKs or other runtimes that were installed on purpose.
+///
+/// The actual work is done by the garbage collector of the repository, which treats our stream
+/// refs as roots.  It doesn't tell us what it would remove, so `dry_run` makes an estimate of its
+/// own: the files of uninstalled images are found, but their streams and images aren't, so the
+/// real thing can reclaim a bit more.
+pub(crate) fn prune<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    unused_runtimes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let (mut roots, mut unused) = find_roots(repo)?;
+    if !unused_runtimes {
+        for r#ref in &unused {
+            println!("Not used by any app: {}", format_ref(r#ref));
+        }
+        if !unused.is_empty() {
+            println!("Use --unused-runtimes to uninstall these");
+        }
+        roots.append(&mut unused);
+    }
+    println!("Keeping objects used by {} installed refs", roots.len());
+
+    for r#ref in &unused {
+        if dry_run {
+            println!("Would uninstall unused {}", format_ref(r#ref));
+        } else {
+            uninstall(repo, r#ref, true)?;
+            println!("Uninstalled unused {}", format_ref(r#ref));
+        }
+    }
+
+    if dry_run {
+        let mut live = HashSet::new();
+        for r#ref in &roots {
+            if let Some(filesystem) = installed_filesystem(repo, r#ref)? {
+                collect_objects(&filesystem.root, &mut live);
+            }
+        }
+        collect_named_objects(repo.objects_dir()?, "streams", &mut live)?;
+        collect_named_objects(repo.objects_dir()?, "images", &mut live)?;
+
+        let objects = list_objects(repo)?;
+        let (count, size) = total_size(
+            objects
+                .iter()
+                .filter(|(id, _)| !live.contains(*id))
+                .map(|(_, size)| size),
+        );
+        println!(
+            "Would remove at least {count} objects, reclaiming {}",
+            format_size(size)
+        );
+    } else {
+        let before = list_objects(repo)?;
+        repo.gc().context("Unable to prune the repository")?;
+        let after = list_objects(repo)?;
+
+        let (count, size) = total_size(
+            before
+                .iter()
+                .filter(|(id, _)| !after.contains_key(*id))
+                .map(|(_, size)| size),
+        );
+        println!("Removed {count} objects, reclaiming {}", format_size(size));
+    }
+
+    Ok(())
+}
+
+/// The names of the streams of an installed ref: the one of its config, which its stream ref
+/// points at, and the ones of its layers, which are named after their diff IDs.
+fn image_streams<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<Vec<String>> {
+    let Some(target) = read_stream_ref(repo, r#ref)? else {
+        return Ok(vec![]);
+    };
+    let name = format!("refs/flatpak-rs/{ref}");
+    let (config, _) = composefs_oci::open_config(repo, &name, None)
+        .with_context(|| format!("Unable to read the config of {ref}"))?;
+
+    let mut streams = vec![target.rsplit('/').next().unwrap_or(&target).to_string()];
+    for diff_id in config.rootfs().diff_ids() {
+        streams.push(diff_id.trim_start_matches("sha256:").to_string());
+    }
+    Ok(streams)
+}
+
+/// The image that an installed ref had before an update replaced it, for `--prune-old`.
+pub(crate) struct OldImage<ObjectID> {
+    streams: Vec<String>,
+    objects: HashSet<ObjectID>,
+}
+
+impl<ObjectID: FsVerityHashValue> OldImage<ObjectID> {
+    /// Reads what `ref` is installed from right now, or None if it isn't installed.  This has to
+    /// happen before the pull replaces its stream ref.
+    pub(crate) fn read(repo: &Repository<ObjectID>, r#ref: &Ref) -> Result<Option<Self>> {
+        let Some(filesystem) = installed_filesystem(repo, r#ref)? else {
+            return Ok(None);
+        };
+        let mut objects = HashSet::new();
+        collect_objects(&filesystem.root, &mut objects);
+
+        Ok(Some(Self {
+            streams: image_streams(repo, r#ref)?,
+            objects,
+        }))
+    }
+}
+
+/// Removes the images that got replaced by an installation or an update.  Unlike [`prune`], this
+/// leaves the rest of the repository alone: only the streams and objects of the old images go,
+/// and only if none of the installed refs still use them.  The metadata image that was committed
+/// for an old image is left for `prune`.
+///
+/// Nothing happens while a sandbox or another installation holds the repository lock: a sandbox
+/// may still need to open the files of the old images, and an installation may have just pulled
+/// objects that it hasn't referenced yet.
+pub(crate) fn prune_old_images<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    old: Vec<OldImage<ObjectID>>,
+) -> Result<()> {
+    let Some(_lock) = RepoLock::try_exclusive(repo)? else {
+        println!(
+            "Not pruning: the repository is in use by a running sandbox or another installation"
+        );
+        return Ok(());
+    };
+
+    let objects_dir = repo.objects_dir()?;
+    let streams_dir = open_dir(objects_dir, "../streams").context("Unable to open streams")?;
+
+    let mut live_streams = HashSet::new();
+    let mut live = HashSet::new();
+    for r#ref in installed_refs(repo)? {
+        live_streams.extend(image_streams(repo, &r#ref)?);
+        if let Some(filesystem) = installed_filesystem(repo, &r#ref)? {
+            collect_objects(&filesystem.root, &mut live);
+        }
+    }
+
+    let mut garbage = HashSet::new();
+    for image in old {
+        for stream in image.streams.iter().filter(|s| !live_streams.contains(*s)) {
+            // The stream is a symlink to the object holding its contents, which goes as well
+            let Ok(target) = readlinkat(&streams_dir, stream, vec![]) else {
+                continue;
+            };
+            garbage.extend(parse_object_link(&target.to_string_lossy()));
+            match unlinkat(&streams_dir, stream, AtFlags::empty()) {
+                Ok(()) | Err(Errno::NOENT) => {}
+                Err(err) => {
+                    Err(err).with_context(|| format!("Unable to remove stream {stream}"))?
+                }
+            }
+        }
+        garbage.extend(image.objects);
+    }
+
+    // Whatever is still named can't go, even if it's shared with one of the old images
+    collect_named_objects(objects_dir, "streams", &mut live)?;
+    collect_named_objects(objects_dir, "images", &mut live)?;
+
+    let (mut count, mut size) = (0, 0);
+    for id in garbage.difference(&live) {
+        let hex = id.to_hex();
+        let path = format!("{}/{}", &hex[..2], &hex[2..]);
+        let stat = match statat(objects_dir, &path, AtFlags::SYMLINK_NOFOLLOW) {
+            Ok(stat) => stat,
+            Err(Errno::NOENT) => continue,
+            Err(err) => Err(err).with_context(|| format!("Unable to stat object {hex}"))?,
+        };
+        unlinkat(objects_dir, &path, AtFlags::empty())
+            .with_context(|| format!("Unable to remove object {hex}"))?;
+        count += 1;
+        size += stat.st_size as u64;
+    }
+    println!("Removed {count} objects, reclaiming {}", format_size(size));
+
+    Ok(())
+}
@@ -1,9 +1,16 @@
 // TODO: add remote: support
 
-use std::fmt;
+use std::{borrow::Borrow, fmt};
 
 use anyhow::ensure;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// What a ref refers to: the first part of it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RefKind {
+    App,
+    Runtime,
+}
 
 // don't store indexes: scanning for the correct parts is fast enough...
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
@@ -30,6 +37,14 @@ impl AsRef<str> for Ref {
     }
 }
 
+// Allows looking up maps keyed by Ref with a plain &str.  This is fine because the derived Hash
+// and Eq only look at the string, just like the ones of str.
+impl Borrow<str> for Ref {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
 impl fmt::Display for Ref {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.0.fmt(f)
@@ -46,6 +61,15 @@ impl<'de> Deserialize<'de> for Ref {
     }
 }
 
+impl Serialize for Ref {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
 #[allow(dead_code)]
 impl Ref {
     fn part(&self, n: usize) -> &str {
@@ -74,12 +98,21 @@ impl Ref {
         None
     }
 
+    pub(crate) fn get_kind(&self) -> RefKind {
+        match self.part(0) {
+            "app" => RefKind::App,
+            "runtime" => RefKind::Runtime,
+            // SAFETY: we checked the first part on construction
+            other => unreachable!("Invalid kind of ref {other}"),
+        }
+    }
+
     pub(crate) fn is_runtime(&self) -> bool {
-        self.part(0) == "runtime"
+        self.get_kind() == RefKind::Runtime
     }
 
     pub(crate) fn is_app(&self) -> bool {
-        self.part(0) == "app"
+        self.get_kind() == RefKind::App
     }
 
     pub(crate) fn get_id(&self) -> &str {
@@ -1,25 +1,28 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStringExt,
+};
+
 use anyhow::{Context, Result, ensure};
 use rustix::{
     fd::{AsRawFd, OwnedFd},
-    io::{IoSlice, writev},
-    pipe::{PipeFlags, pipe_with},
+    fs::{MemfdFlags, memfd_create},
 };
 
-// Just store things directly in the pipe.
+// Just store things directly in a memfd.  Unlike a pipe, it doesn't fill up: the commandline can
+// be as long as the kernel allows.
 pub(super) struct ArgsFdBuilder {
-    read: OwnedFd,
-    write: OwnedFd,
+    file: File,
 }
 
 impl ArgsFdBuilder {
     pub(super) fn new() -> Result<Self> {
-        // We store directly into the pipe as we get the arguments under the assumption that we'll
-        // have more than enough space: the default size is 64KiB.  If it fills up, we want to get
-        // an error about it, so let's use NONBLOCK: we need to handle errors in the .add() case
-        // anyway because of checking for "\0".
-        let (read, write) = pipe_with(PipeFlags::CLOEXEC | PipeFlags::NONBLOCK)
-            .context("Unable to create a pipe")?;
-        Ok(Self { read, write })
+        let fd = memfd_create("args", MemfdFlags::CLOEXEC).context("Unable to create a memfd")?;
+        Ok(Self {
+            file: File::from(fd),
+        })
     }
 
     pub(super) fn add(&self, arg: impl AsRef<[u8]>) -> Result<()> {
@@ -28,8 +31,11 @@ impl ArgsFdBuilder {
             arg.iter().all(|c| *c != 0),
             "Cannot add commandline argument to argfd containing nuls"
         );
-        let iovec = [IoSlice::new(arg), IoSlice::new(b"\0")];
-        writev(&self.write, &iovec)?;
+        // write_all() deals with short writes
+        (&self.file)
+            .write_all(arg)
+            .and_then(|()| (&self.file).write_all(b"\0"))
+            .context("Unable to store commandline argument")?;
         Ok(())
     }
 
@@ -40,12 +46,31 @@ impl ArgsFdBuilder {
         Ok(())
     }
 
-    pub(super) fn done(self) -> OwnedFd {
-        // We drop the writer so the reader can successfully read to EOF
-        self.read
+    pub(super) fn done(mut self) -> Result<OwnedFd> {
+        // The reader starts from wherever we are
+        self.file
+            .seek(SeekFrom::Start(0))
+            .context("Unable to rewind arguments")?;
+        Ok(self.file.into())
     }
 }
 
+/// Reads back the arguments from the fd returned by [`ArgsFdBuilder::done()`].
+pub(super) fn read_args(fd: OwnedFd) -> Result<Vec<OsString>> {
+    let mut data = vec![];
+    File::from(fd)
+        .read_to_end(&mut data)
+        .context("Unable to read arguments")?;
+
+    // Every argument is terminated by a nul, so the last "argument" is empty
+    let mut args: Vec<_> = data
+        .split(|c| *c == 0)
+        .map(|arg| OsString::from_vec(arg.to_vec()))
+        .collect();
+    args.pop();
+    Ok(args)
+}
+
 pub(super) trait ArgsFd {
     fn as_arg(&self) -> String;
 }
@@ -55,3 +80,32 @@ impl ArgsFd for OwnedFd {
         format!("--args={}", self.as_raw_fd())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(args: &[&str]) -> Vec<OsString> {
+        let builder = ArgsFdBuilder::new().unwrap();
+        builder.extend(args).unwrap();
+        read_args(builder.done().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn spaces_and_utf8() {
+        let args = ["--name", "with spaces", "", "grüße 📦", " "];
+        assert_eq!(round_trip(&args), args);
+    }
+
+    #[test]
+    fn longer_than_a_pipe() {
+        let long = "x".repeat(100_000);
+        let args = [long.as_str(), "after", long.as_str()];
+        assert_eq!(round_trip(&args), args);
+    }
+
+    #[test]
+    fn nul_is_rejected() {
+        assert!(ArgsFdBuilder::new().unwrap().add("a\0b").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+
+use super::util::write_to;
+
+/// Resource limits for the sandbox, applied with a cgroup.
+#[derive(Debug)]
+pub(crate) struct Limits {
+    /// In bytes
+    pub(crate) memory_max: Option<u64>,
+    /// Relative to the default weight of 100, from 1 to 10000
+    pub(crate) cpu_weight: Option<u16>,
+}
+
+impl Limits {
+    fn is_empty(&self) -> bool {
+        self.memory_max.is_none() && self.cpu_weight.is_none()
+    }
+}
+
+/// Parses a size in bytes with an optional K, M or G suffix, like `512M`.
+pub(crate) fn parse_size(value: &str) -> Result<u64, String> {
+    let upper = value.to_uppercase();
+    let upper = upper.trim_end_matches('B');
+    let (number, shift) = [('K', 10), ('M', 20), ('G', 30)]
+        .into_iter()
+        .find_map(|(suffix, shift)| Some((upper.strip_suffix(suffix)?, shift)))
+        .unwrap_or((upper, 0));
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size {value}: use a number of bytes, or like 512M or 2G"))
+}
+
+/// A cgroup for the sandbox.  It's created next to our own cgroup, which has to be in a part of
+/// the cgroup v2 hierarchy delegated to the user, like the ones systemd creates for apps.  It gets
+/// removed when dropped.
+pub(super) struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Finds our own cgroup in the unified hierarchy.
+    fn own_path() -> Result<PathBuf> {
+        let cgroups =
+            fs::read_to_string("/proc/self/cgroup").context("Unable to determine our cgroup")?;
+        let Some(path) = cgroups.lines().find_map(|line| line.strip_prefix("0::")) else {
+            bail!("Resource limits need cgroup v2");
+        };
+
+        Ok(Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/')))
+    }
+
+    /// Creates the cgroup and applies the limits.  Returns None if there are no limits to apply.
+    pub(super) fn create(name: &str, limits: &Limits) -> Result<Option<Self>> {
+        if limits.is_empty() {
+            return Ok(None);
+        }
+
+        let own = Self::own_path()?;
+        let Some(parent) = own.parent() else {
+            bail!("Unable to create a cgroup next to the root cgroup");
+        };
+
+        // The controllers need to be enabled in the parent for the limits to exist in our cgroup
+        let subtree_control = parent.join("cgroup.subtree_control");
+        let subtree_control = subtree_control.to_str().context("Invalid cgroup path")?;
+        let controllers = [
+            limits.memory_max.map(|_| "+memory"),
+            limits.cpu_weight.map(|_| "+cpu"),
+        ];
+        for controller in controllers.into_iter().flatten() {
+            write_to(subtree_control, controller).with_context(|| {
+                format!("Unable to enable {controller} (is the cgroup delegated to you?)")
+            })?;
+        }
+
+        let cgroup = Self {
+            path: parent.join(name),
+        };
+        fs::create_dir(&cgroup.path)
+            .with_context(|| format!("Unable to create cgroup {:?}", cgroup.path))?;
+
+        if let Some(memory_max) = limits.memory_max {
+            cgroup.write("memory.max", &memory_max.to_string())?;
+        }
+        if let Some(cpu_weight) = limits.cpu_weight {
+            cgroup.write("cpu.weight", &cpu_weight.to_string())?;
+        }
+
+        Ok(Some(cgroup))
+    }
+
+    fn write(&self, name: &str, value: &str) -> Result<()> {
+        let path = self.path.join(name);
+        write_to(path.to_str().context("Invalid cgroup path")?, value)
+    }
+
+    /// Moves the calling process into the cgroup.  Its children will follow.
+    pub(super) fn enter(&self) -> Result<()> {
+        self.write("cgroup.procs", "0")
+    }
+
+    /// Whether there are still processes in the cgroup.
+    fn is_populated(&self) -> Result<bool> {
+        let path = self.path.join("cgroup.events");
+        let events =
+            fs::read_to_string(&path).with_context(|| format!("Unable to read {path:?}"))?;
+        Ok(!events.lines().any(|line| line == "populated 0"))
+    }
+
+    /// Removes the cgroup.  Even after we've reaped everything in it, the kernel can take a
+    /// moment to notice that it's empty, and removing it fails with EBUSY until then.  We give it
+    /// a second.
+    fn remove(&self) -> Result<()> {
+        for _ in 0..100 {
+            if !self.is_populated()? {
+                match fs::remove_dir(&self.path) {
+                    Ok(()) => return Ok(()),
+                    Err(err) if err.kind() == ErrorKind::ResourceBusy => {}
+                    Err(err) => Err(err)?,
+                }
+            }
+            sleep(Duration::from_millis(10));
+        }
+        bail!("There are still processes in it");
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if !self.path.exists() {
+            return;
+        }
+        if let Err(err) = self.remove() {
+            log::warn!("Unable to remove cgroup {:?}: {err:#}", self.path);
+        }
+    }
+}
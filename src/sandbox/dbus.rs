@@ -1,36 +1,178 @@
-use std::process::Command;
+use std::{
+    env, fmt,
+    process::{Command, Stdio},
+};
 
-use anyhow::Result;
-use rustix::fd::AsFd;
-use rustix::io::fcntl_dupfd_cloexec;
+use anyhow::{Context, Result, anyhow, bail};
+use rustix::{
+    fd::{AsFd, AsRawFd, OwnedFd},
+    fs::{CWD, OFlags},
+    io::{fcntl_dupfd_cloexec, read},
+    pipe::{PipeFlags, pipe_with},
+};
 
 use super::{
     argsfd::{ArgsFd, ArgsFdBuilder},
-    util::nameat,
+    util::{nameat, open_path},
     withfds::WithFds,
 };
 
+/// The buses that we proxy into the sandbox.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum Bus {
+    Session,
+    System,
+    Accessibility,
+}
+
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Session => "session bus",
+            Self::System => "system bus",
+            Self::Accessibility => "accessibility bus",
+        })
+    }
+}
+
+/// What apps may do on the accessibility bus: the same calls that flatpak allows, which are
+/// enough to register with the screen reader.
+const A11Y_BUS_CALLS: &[&str] = &[
+    "org.a11y.atspi.Socket.Embed@/org/a11y/atspi/accessible/root",
+    "org.a11y.atspi.Socket.Unembed@/org/a11y/atspi/accessible/root",
+    "org.a11y.atspi.Registry.GetRegisteredEvents@/org/a11y/atspi/registry",
+    "org.a11y.atspi.DeviceEventController.GetKeystrokeListeners@/org/a11y/atspi/registry/deviceeventcontroller",
+    "org.a11y.atspi.DeviceEventController.GetDeviceEventListeners@/org/a11y/atspi/registry/deviceeventcontroller",
+    "org.a11y.atspi.DeviceEventController.NotifyListenersSync@/org/a11y/atspi/registry/deviceeventcontroller",
+    "org.a11y.atspi.DeviceEventController.NotifyListenersAsync@/org/a11y/atspi/registry/deviceeventcontroller",
+];
+
+/// Turns a bus policy from the manifest into filtering flags for xdg-dbus-proxy.  On the session
+/// bus, the app may always own its own name and talk to the portals, which are meant for it.  On
+/// the accessibility bus, it may only make the calls in A11Y_BUS_CALLS.
+pub(super) fn filter_flags<'a>(
+    bus: Bus,
+    app_id: &str,
+    policy: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Vec<String> {
+    let mut flags = vec!["--filter".to_string()];
+    if let Bus::Session = bus {
+        flags.push(format!("--own={app_id}"));
+        flags.push(format!("--own={app_id}.*"));
+        flags.push("--talk=org.freedesktop.portal.*".to_string());
+    }
+    if let Bus::Accessibility = bus {
+        flags.push("--sloppy-names".to_string());
+        for call in A11Y_BUS_CALLS {
+            flags.push(format!("--call=org.a11y.atspi.Registry={call}"));
+        }
+    }
+
+    for (name, access) in policy {
+        match access {
+            "see" | "talk" | "own" => flags.push(format!("--{access}={name}")),
+            "none" => {}
+            other => log::debug!("Ignoring unknown bus policy {other} for {name}"),
+        }
+    }
+
+    flags
+}
+
+/// Finds the socket of the accessibility bus of the host and opens it.  Unless AT_SPI_BUS_ADDRESS
+/// is set, we ask the session bus for the address, which starts the accessibility bus if it
+/// isn't running yet.  Returns None if there's no accessibility bus that we can share: it's only
+/// nice to have, so failing to find it is logged but not an error.
+pub(super) fn find_a11y_bus() -> Result<Option<OwnedFd>> {
+    match open_a11y_bus() {
+        Ok(socket) => Ok(Some(socket)),
+        Err(err) => {
+            log::debug!("Not sharing the accessibility bus: {err:#}");
+            Ok(None)
+        }
+    }
+}
+
+fn open_a11y_bus() -> Result<OwnedFd> {
+    let address = match env::var_os("AT_SPI_BUS_ADDRESS") {
+        Some(address) => address
+            .into_string()
+            .map_err(|address| anyhow!("Invalid AT_SPI_BUS_ADDRESS {address:?}"))?,
+        None => {
+            let output = Command::new("dbus-send")
+                .args(["--session", "--print-reply=literal", "--dest=org.a11y.Bus"])
+                .args(["/org/a11y/bus", "org.a11y.Bus.GetAddress"])
+                .stderr(Stdio::null())
+                .output()
+                .context("Unable to run dbus-send to find the accessibility bus")?;
+            if !output.status.success() {
+                bail!(
+                    "Unable to find the accessibility bus: dbus-send {}",
+                    output.status
+                );
+            }
+            String::from_utf8(output.stdout)
+                .context("Invalid accessibility bus address")?
+                .trim()
+                .to_string()
+        }
+    };
+
+    // Only sockets in the filesystem can be shared, not abstract ones
+    let path = address
+        .split(';')
+        .filter_map(|address| address.strip_prefix("unix:"))
+        .find_map(|params| {
+            params
+                .split(',')
+                .find_map(|param| param.strip_prefix("path="))
+        });
+    let Some(path) = path else {
+        bail!("Unsupported accessibility bus address {address:?}");
+    };
+
+    open_path(CWD, path, OFlags::empty())
+        .with_context(|| format!("Cannot open host accessibility bus socket {path:?}"))
+}
+
+/// Starts xdg-dbus-proxy to listen on `sandbox_name` and forward to the bus at `host_name`,
+/// filtered according to `flags`.  Waits until the proxy is ready and returns an fd that keeps
+/// it running for as long as it's open.
 pub(crate) fn dbus_proxy(
+    bus: Bus,
     sandbox_dirfd: impl AsFd,
     sandbox_name: &str,
     host_dirfd: impl AsFd,
     host_name: &str,
-    flags: &[&str],
-) -> Result<()> {
+    flags: &[String],
+) -> Result<OwnedFd> {
     let host_dirfd = fcntl_dupfd_cloexec(host_dirfd, 0)?;
     let sandbox_dirfd = fcntl_dupfd_cloexec(sandbox_dirfd, 0)?;
 
+    // The proxy writes a byte to the pipe once it's listening, and exits when we close our end
+    let (sync_fd, proxy_sync_fd) =
+        pipe_with(PipeFlags::CLOEXEC).context("Unable to create a pipe")?;
+
     let args = ArgsFdBuilder::new()?;
+    args.add(format!("--fd={}", proxy_sync_fd.as_raw_fd()))?;
     args.add(format!("unix:path={}", nameat(&host_dirfd, host_name)))?;
     args.add(nameat(&sandbox_dirfd, sandbox_name))?;
     args.add("--log")?;
     args.extend(flags)?;
-    let args_fd = args.done();
+    let args_fd = args.done()?;
 
     Command::new("xdg-dbus-proxy")
         .arg(args_fd.as_arg())
-        .with_fds([host_dirfd, sandbox_dirfd, args_fd])
-        .spawn()?;
+        .with_fds([host_dirfd, sandbox_dirfd, args_fd, proxy_sync_fd])
+        .spawn()
+        .with_context(|| format!("Unable to start xdg-dbus-proxy for the {bus}"))?;
+
+    let mut buffer = [0u8; 1];
+    let ready = read(&sync_fd, &mut buffer)
+        .with_context(|| format!("Unable to wait for xdg-dbus-proxy for the {bus}"))?;
+    if ready == 0 {
+        bail!("xdg-dbus-proxy for the {bus} exited during startup");
+    }
 
-    Ok(())
+    Ok(sync_fd)
 }
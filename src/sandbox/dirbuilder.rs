@@ -5,19 +5,32 @@ use std::{
 
 use anyhow::{Context, Result};
 use rustix::{
-    fd::{AsFd, BorrowedFd, OwnedFd},
-    fs::{OFlags, mkdirat, openat, symlinkat},
+    fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    fs::{FileType, OFlags, fstat, mkdirat, openat, symlinkat},
     io::Errno,
     path::Arg as PathArg,
 };
 
 use super::{
     mounthandle::MountHandle,
-    util::{filter_errno, open_dir},
+    util::{filter_errno, open_dir, open_path},
 };
 
 pub(super) struct DirBuilder<'a> {
     dirfd: &'a OwnedFd,
+    /// The path of the directory in the sandbox, if we're tracing what gets set up there
+    trace: Option<String>,
+}
+
+/// Describes where a bind mount comes from on the host, for tracing.
+fn describe_source(dirfd: impl AsFd, name: &str) -> String {
+    let dir = std::fs::read_link(format!("/proc/self/fd/{}", dirfd.as_fd().as_raw_fd()))
+        .unwrap_or_default();
+    if name.is_empty() {
+        dir.display().to_string()
+    } else {
+        dir.join(name).display().to_string()
+    }
 }
 
 impl<'a> DirBuilder<'a> {
@@ -32,7 +45,29 @@ impl<'a> DirBuilder<'a> {
     const FILE_PERMISSION: u32 = 0o644;
 
     pub(super) fn new(dirfd: &'a OwnedFd) -> Self {
-        Self { dirfd }
+        Self { dirfd, trace: None }
+    }
+
+    /// Like new(), but prints what gets set up below the directory, which is the root of the
+    /// sandbox.
+    pub(super) fn new_traced(dirfd: &'a OwnedFd) -> Self {
+        Self {
+            dirfd,
+            trace: Some(String::new()),
+        }
+    }
+
+    fn trace(&self, what: &str, name: &str, detail: impl FnOnce() -> String) {
+        if let Some(path) = &self.trace {
+            println!("{what:<8} {path}/{name}{}", detail());
+        }
+    }
+
+    fn child<'b>(&self, dirfd: &'b OwnedFd, name: &str) -> DirBuilder<'b> {
+        DirBuilder {
+            dirfd,
+            trace: self.trace.as_ref().map(|path| format!("{path}/{name}")),
+        }
     }
 
     pub(super) fn create_dir(&self, name: &str, mode: u32, exist_ok: bool) -> Result<OwnedFd> {
@@ -79,14 +114,17 @@ impl<'a> DirBuilder<'a> {
             .create_dir(name, Self::DIR_PERMISSION, false)
             .with_context(|| format!("Failed to create subdirectory {name}"))?;
 
-        populate(DirBuilder { dirfd }).with_context(|| format!("Failed to populate subdir {name}"))
+        populate(self.child(dirfd, name))
+            .with_context(|| format!("Failed to populate subdir {name}"))
     }
 
     pub(super) fn write(&self, name: &str, content: &str) -> Result<()> {
+        self.trace("file", name, String::new);
         Ok(File::from(self.create_file(name)?).write_all(content.as_bytes())?)
     }
 
     pub(super) fn tee(&self, name: &str) -> Result<BufWriter<File>> {
+        self.trace("file", name, String::new);
         Ok(BufWriter::new(File::from(self.create_file(name)?)))
     }
 
@@ -95,16 +133,19 @@ impl<'a> DirBuilder<'a> {
         name: &str,
         populate: impl Fn(BufWriter<File>) -> Result<()>,
     ) -> Result<()> {
+        self.trace("file", name, String::new);
         populate(BufWriter::new(File::from(self.create_file(name)?)))
             .with_context(|| format!("Failed to write to file {}", name))
     }
 
     pub(super) fn symlink(&self, name: &str, target: &str) -> Result<()> {
+        self.trace("symlink", name, || format!(" -> {target}"));
         symlinkat(target, self.dirfd, name)
             .with_context(|| format!("Failed to symlink {name:?} -> {target:?}"))
     }
 
     pub(super) fn mount(&self, name: &str, mnt: MountHandle) -> Result<()> {
+        self.trace("mount", name, String::new);
         mnt.move_to(self.create_dir(name, Self::DIR_PERMISSION, false)?, "")
     }
 
@@ -114,11 +155,10 @@ impl<'a> DirBuilder<'a> {
         mnt: MountHandle,
         mut populate: impl FnMut(DirBuilder) -> Result<()>,
     ) -> Result<()> {
+        self.trace("mount", name, String::new);
         mnt.move_to(self.create_dir(name, Self::DIR_PERMISSION, false)?, "")?;
-        populate(DirBuilder {
-            dirfd: &mnt.mountfd,
-        })
-        .with_context(|| format!("Failed to populate mount {name}"))
+        populate(self.child(&mnt.mountfd, name))
+            .with_context(|| format!("Failed to populate mount {name}"))
     }
 
     pub(super) fn bind_dir(
@@ -127,7 +167,48 @@ impl<'a> DirBuilder<'a> {
         from_dirfd: impl AsFd,
         from_name: impl PathArg,
     ) -> Result<()> {
-        self.mount(name, MountHandle::clone_recursive(from_dirfd, from_name)?)
+        self.trace("bind", name, || {
+            format!(
+                " <- {}",
+                describe_source(&from_dirfd, &from_name.to_string_lossy())
+            )
+        });
+        let mnt = MountHandle::clone_recursive(from_dirfd, from_name)?;
+        mnt.move_to(self.create_dir(name, Self::DIR_PERMISSION, false)?, "")
+    }
+
+    /// Like bind_dir(), but optionally readonly, and the mountpoint may already exist: the bind
+    /// gets mounted over whatever is there.  The source can also be a file, like `~/.gitconfig`,
+    /// and then the mountpoint is a file too.
+    pub(super) fn bind_dir_over(
+        &self,
+        name: &str,
+        from_dirfd: impl AsFd,
+        from_name: impl PathArg,
+        readonly: bool,
+    ) -> Result<()> {
+        self.trace("bind", name, || {
+            let readonly = if readonly { " (read-only)" } else { "" };
+            format!(
+                " <- {}{readonly}",
+                describe_source(&from_dirfd, &from_name.to_string_lossy())
+            )
+        });
+        let mnt = MountHandle::clone_recursive(from_dirfd, from_name)?;
+        if readonly {
+            mnt.make_readonly_recursive()?;
+        }
+        let is_dir = FileType::from_raw_mode(fstat(&mnt.mountfd)?.st_mode) == FileType::Directory;
+        let mountpoint = if is_dir {
+            self.create_dir(name, Self::DIR_PERMISSION, true)?
+        } else if let Some(file) =
+            filter_errno(open_path(self.dirfd, name, OFlags::empty()), Errno::NOENT)?
+        {
+            file
+        } else {
+            self.create_file(name)?
+        };
+        mnt.move_to(mountpoint, "")
     }
 
     pub(super) fn bind_file(
@@ -136,6 +217,12 @@ impl<'a> DirBuilder<'a> {
         from_dirfd: impl AsFd,
         from_name: impl PathArg,
     ) -> Result<()> {
+        self.trace("bind", name, || {
+            format!(
+                " <- {}",
+                describe_source(&from_dirfd, &from_name.to_string_lossy())
+            )
+        });
         MountHandle::clone(from_dirfd, from_name)?.move_to(self.create_file(name)?, "")
     }
 }
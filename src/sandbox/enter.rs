@@ -0,0 +1,99 @@
+use std::{
+    fs::{self, File},
+    os::unix::{fs::MetadataExt, process::ExitStatusExt},
+    process::Command,
+};
+
+use anyhow::{Context, Result};
+use rustix::{
+    fd::AsFd,
+    fs::CWD,
+    process::{chroot, fchdir, getgid, getuid},
+    thread::{LinkNameSpaceType, move_into_link_name_space, set_thread_gid, set_thread_uid},
+};
+
+use super::{
+    pidns::die_from_signal,
+    seccomp::FilterInfo,
+    util::{drop_all_capabilities, open_dir},
+};
+use crate::instance::RunningInstance;
+
+/// The namespaces that we join, in order: the user namespace first, since it owns the others.
+/// The process we join is the one that stays behind to serve FUSE, so it's not in the PID
+/// namespace itself, but its children are.
+const NAMESPACES: &[(&str, LinkNameSpaceType)] = &[
+    ("user", LinkNameSpaceType::User),
+    ("mnt", LinkNameSpaceType::Mount),
+    ("uts", LinkNameSpaceType::HostNameAndNISDomainName),
+    ("net", LinkNameSpaceType::Network),
+    ("pid_for_children", LinkNameSpaceType::ProcessID),
+];
+
+/// Checks if the process is in a different namespace than us.  We can't join the namespaces
+/// that the sandbox shares with the host: they don't belong to its user namespace.
+fn is_unshared(pid: u32, name: &str) -> Result<bool> {
+    let ours = fs::metadata(format!("/proc/self/ns/{name}"))?;
+    let theirs = fs::metadata(format!("/proc/{pid}/ns/{name}"))
+        .with_context(|| format!("Unable to inspect namespaces of process {pid}"))?;
+    Ok((ours.dev(), ours.ino()) != (theirs.dev(), theirs.ino()))
+}
+
+/// Runs a command inside of a running instance, for debugging.  We join the namespaces of the
+/// sandbox and its root directory, and drop our capabilities and install the seccomp filter like
+/// the sandbox does, but the environment is a minimal one.  Returns the exit code, or dies
+/// from the same signal as the command.
+pub(crate) fn enter_instance(id: &str, command: &[String]) -> Result<i32> {
+    let instance = RunningInstance::get(id)?;
+    let pid = instance.pid;
+    let filter = FilterInfo::read(&instance.info)
+        .with_context(|| format!("Invalid seccomp settings in instance {id}"))?;
+
+    // Open everything up front: once we're in the mount namespace, the host /proc is gone
+    let root = open_dir(CWD, format!("/proc/{pid}/root"))
+        .with_context(|| format!("Unable to open root directory of process {pid}"))?;
+    let mut namespaces = vec![];
+    for &(name, kind) in NAMESPACES {
+        if is_unshared(pid, name)? {
+            let fd = File::open(format!("/proc/{pid}/ns/{name}"))
+                .with_context(|| format!("Unable to open {name} namespace of process {pid}"))?;
+            namespaces.push((name, kind, fd));
+        }
+    }
+
+    for (name, kind, fd) in &namespaces {
+        move_into_link_name_space(fd.as_fd(), Some(*kind))
+            .with_context(|| format!("Unable to enter {name} namespace"))?;
+    }
+    fchdir(&root).context("Unable to change to sandbox root directory")?;
+    chroot(".").context("Unable to change root directory")?;
+
+    // Our uid as seen from the user namespace is the one the sandbox runs as
+    set_thread_gid(getgid()).context("Unable to drop capabilities")?;
+    set_thread_uid(getuid()).context("Unable to drop capabilities")?;
+    if getuid().is_root() {
+        drop_all_capabilities()?;
+    }
+    if let Some(filter) = filter {
+        filter.install()?;
+    }
+
+    let (program, args) = command
+        .split_first()
+        .map_or(("/bin/sh", &[][..]), |(program, args)| {
+            (program.as_str(), args)
+        });
+    let status = Command::new(program)
+        .args(args)
+        .current_dir("/")
+        .env("PATH", "/app/bin:/usr/bin")
+        .env("FLATPAK_ID", instance.r#ref.get_id())
+        .env("PS1", "[📦 $FLATPAK_ID \\W]\\$ ")
+        .status()
+        .with_context(|| format!("Unable to run {program:?}"))?;
+
+    if let Some(signal) = status.signal() {
+        die_from_signal(signal);
+    }
+    Ok(status.code().unwrap_or(255))
+}
@@ -0,0 +1,85 @@
+use std::{fmt, str::FromStr};
+
+/// Top-level directories of the sandbox that host paths can't be mounted over.
+const RESERVED: &[&str] = &[
+    "app", "bin", "dev", "etc", "lib", "lib64", "proc", "sbin", "sys", "usr",
+];
+
+/// How a host path is exposed to the sandbox.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Access {
+    ReadWrite,
+    ReadOnly,
+    /// Read-write, and created on the host if it doesn't exist yet
+    Create,
+}
+
+/// A host path exposed to the sandbox at the same location, from `--filesystem` or the
+/// `filesystems=` permission of the app.  Written like `/srv/data:ro` or `~/Music`.
+#[derive(Clone, Debug)]
+pub(crate) struct Filesystem {
+    /// Relative to the home directory if `home_relative`, otherwise relative to `/`
+    pub(super) path: String,
+    pub(super) home_relative: bool,
+    pub(super) access: Access,
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.home_relative, self.path.as_str()) {
+            (true, "") => write!(f, "~")?,
+            (true, path) => write!(f, "~/{path}")?,
+            (false, path) => write!(f, "/{path}")?,
+        }
+        match self.access {
+            Access::ReadWrite => Ok(()),
+            Access::ReadOnly => write!(f, ":ro"),
+            Access::Create => write!(f, ":create"),
+        }
+    }
+}
+
+impl FromStr for Filesystem {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (path, access) = match value.rsplit_once(':') {
+            Some((path, "ro")) => (path, Access::ReadOnly),
+            Some((path, "rw")) => (path, Access::ReadWrite),
+            Some((path, "create")) => (path, Access::Create),
+            Some((_, other)) => {
+                return Err(format!("Unknown access {other:?}: use ro, rw or create"));
+            }
+            None => (value, Access::ReadWrite),
+        };
+
+        let (path, home_relative) = if let Some(path) = path.strip_prefix('/') {
+            (path, false)
+        } else if path == "~" {
+            ("", true)
+        } else if let Some(path) = path.strip_prefix("~/") {
+            (path, true)
+        } else if path == "home" || path == "host" {
+            return Err(format!("{path} can't be mounted: use --share=home"));
+        } else {
+            return Err(format!("{path} must be an absolute path or start with ~/"));
+        };
+
+        let path = path.trim_end_matches('/');
+        if path.split('/').any(|part| part == ".." || part == ".") {
+            return Err(format!("{value} must not contain . or .."));
+        }
+        if !home_relative {
+            let top = path.split('/').next().unwrap_or_default();
+            if top.is_empty() || RESERVED.contains(&top) {
+                return Err(format!("/{top} is reserved for the sandbox"));
+            }
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            home_relative,
+            access,
+        })
+    }
+}
@@ -0,0 +1,123 @@
+use std::{fs::File, io::Write};
+
+use anyhow::{Context, Result};
+use composefs::{fsverity::FsVerityHashValue, repository::Repository};
+use rustix::{
+    fd::OwnedFd,
+    fs::{AtFlags, Dir, Mode, OFlags, openat, renameat, unlinkat},
+    io::Errno,
+};
+
+use super::util::filter_errno;
+use crate::{
+    install::{create_parents, open_dir, read_stream_ref},
+    r#ref::Ref,
+};
+
+/// The `/etc/ld.so.cache` that ldconfig generated for an app (or runtime), kept in our own
+/// directory next to the repository as `ld.so.cache/{ref}/{key}`.  The key names the installed
+/// images of the runtime and the app, so a new version of either of them gets a new cache.
+pub(super) struct LdCache {
+    dirfd: OwnedFd,
+    key: String,
+}
+
+impl LdCache {
+    /// Opens (and creates, if needed) the cache directory for `ref`, which runs on `runtime`.
+    pub(super) fn new<ObjectID: FsVerityHashValue>(
+        repo: &Repository<ObjectID>,
+        r#ref: &Ref,
+        runtime: &Ref,
+    ) -> Result<Self> {
+        let mut images = vec![];
+        for r#ref in [runtime, r#ref] {
+            let target =
+                read_stream_ref(repo, r#ref)?.with_context(|| format!("{ref} is not installed"))?;
+            let image = target.rsplit('/').next().unwrap_or(&target).to_string();
+            if !images.contains(&image) {
+                images.push(image);
+            }
+        }
+
+        let path = format!("../flatpak-rs/ld.so.cache/{ref}/");
+        let objects = repo.objects_dir()?;
+        create_parents(objects, &path)?;
+        let dirfd = open_dir(objects, &path).context("Unable to open ld.so.cache directory")?;
+
+        Ok(Self {
+            dirfd,
+            key: images.join("+"),
+        })
+    }
+
+    /// Opens the cached file, if we have one for the current images.
+    pub(super) fn open(&self) -> Result<Option<OwnedFd>> {
+        let flags = OFlags::RDONLY | OFlags::CLOEXEC;
+        filter_errno(
+            openat(&self.dirfd, &self.key, flags, Mode::empty()),
+            Errno::NOENT,
+        )
+        .context("Unable to open cached ld.so.cache")
+    }
+
+    /// Stores the file that ldconfig generated, and removes the ones for older images.
+    pub(super) fn store(&self, path: &str) -> Result<()> {
+        let content = std::fs::read(path).with_context(|| format!("Unable to read {path}"))?;
+
+        // Write it under a temporary name first, so other instances never see half a file
+        let tmp = format!(".{}.tmp", self.key);
+        let flags = OFlags::WRONLY | OFlags::CREATE | OFlags::TRUNC | OFlags::CLOEXEC;
+        let fd = openat(&self.dirfd, &tmp, flags, Mode::from(0o644))
+            .context("Unable to create cached ld.so.cache")?;
+        File::from(fd)
+            .write_all(&content)
+            .context("Unable to write cached ld.so.cache")?;
+        renameat(&self.dirfd, &tmp, &self.dirfd, &self.key)
+            .context("Unable to store cached ld.so.cache")?;
+
+        for entry in Dir::read_from(&self.dirfd)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().to_str() else {
+                continue;
+            };
+            // Also skips . and .., and the temporary files of other instances
+            if name.starts_with('.') || name == self.key {
+                continue;
+            }
+            match unlinkat(&self.dirfd, name, AtFlags::empty()) {
+                Ok(()) | Err(Errno::NOENT) => {}
+                Err(err) => log::debug!("Unable to remove stale ld.so.cache {name}: {err}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes the cached files of `ref` and their directory, when it gets uninstalled.
+pub(crate) fn remove_ld_cache<ObjectID: FsVerityHashValue>(
+    repo: &Repository<ObjectID>,
+    r#ref: &Ref,
+) -> Result<()> {
+    let path = format!("../flatpak-rs/ld.so.cache/{ref}");
+    let objects = repo.objects_dir()?;
+    let Some(dirfd) = filter_errno(open_dir(objects, &path), Errno::NOENT)
+        .context("Unable to open ld.so.cache directory")?
+    else {
+        return Ok(());
+    };
+
+    for entry in Dir::read_from(&dirfd)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == c"." || name == c".." {
+            continue;
+        }
+        filter_errno(unlinkat(&dirfd, name, AtFlags::empty()), Errno::NOENT)
+            .with_context(|| format!("Unable to remove cached ld.so.cache {name:?}"))?;
+    }
+    filter_errno(unlinkat(objects, &path, AtFlags::REMOVEDIR), Errno::NOENT)
+        .context("Unable to remove ld.so.cache directory")?;
+
+    Ok(())
+}
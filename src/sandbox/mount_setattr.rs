@@ -15,8 +15,10 @@ struct MountAttr {
     userns_fd: u64,
 }
 
+/// Changes the attributes of the mount at `dirfd`, and of all mounts below it if `recursive`.
 pub(crate) fn mount_setattr(
     dirfd: impl AsFd,
+    recursive: bool,
     attr_set: MountAttrFlags,
     attr_clr: MountAttrFlags,
     propagation: MountPropagationFlags,
@@ -28,12 +30,17 @@ pub(crate) fn mount_setattr(
         userns_fd: 0,
     };
 
+    let mut flags = AtFlags::EMPTY_PATH.bits() as c_uint;
+    if recursive {
+        flags |= libc::AT_RECURSIVE as c_uint;
+    }
+
     match unsafe {
         libc::syscall(
             libc::SYS_mount_setattr,
             dirfd.as_fd().as_raw_fd() as c_int,
             c"".as_ptr() as *const c_char,
-            AtFlags::EMPTY_PATH.bits() as c_uint,
+            flags,
             &attr as *const MountAttr,
             std::mem::size_of_val(&attr),
         )
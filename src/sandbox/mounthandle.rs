@@ -30,19 +30,48 @@ impl FsHandle {
         Ok(FsHandle { fsfd, name })
     }
 
+    /// Reads the messages that the kernel queued up on the fs context.  Each is a line like
+    /// `e fuse: Unknown parameter 'foo'`.
+    fn read_messages(&self) -> Vec<String> {
+        let mut messages = vec![];
+        let mut buffer = [0u8; 1024];
+        loop {
+            match rustix::io::read(&self.fsfd, &mut buffer) {
+                Err(_) | Ok(0) => return messages, // ENODATA, among others?
+                Ok(size) => {
+                    let message = String::from_utf8_lossy(&buffer[..size]);
+                    messages.push(message.trim_end().to_string());
+                }
+            }
+        }
+    }
+
+    /// Attaches the messages from the kernel to a failure: they often say what actually went
+    /// wrong, where the errno is just EINVAL.
+    fn check<T>(&self, result: rustix::io::Result<T>) -> Result<T> {
+        result.map_err(|err| {
+            let messages = self.read_messages();
+            if messages.is_empty() {
+                anyhow::Error::from(err)
+            } else {
+                anyhow::Error::from(err).context(messages.join("; "))
+            }
+        })
+    }
+
     pub fn set_flag(&self, flag: &str) -> Result<&Self> {
-        fsconfig_set_flag(self.fsfd.as_fd(), flag)
+        self.check(fsconfig_set_flag(self.fsfd.as_fd(), flag))
             .with_context(|| format!("Failed to set flag {flag:?} on {:?}", self.name))?;
         Ok(self)
     }
     pub fn set_string(&self, key: &str, value: &str) -> Result<&Self> {
-        fsconfig_set_string(self.fsfd.as_fd(), key, value)
+        self.check(fsconfig_set_string(self.fsfd.as_fd(), key, value))
             .with_context(|| format!("Failed to set {key}={value:?} on {:?}", self.name))?;
         Ok(self)
     }
 
     pub fn set_fd(&self, key: &str, value: impl AsFd + fmt::Debug) -> Result<&Self> {
-        fsconfig_set_fd(self.fsfd.as_fd(), key, value.as_fd())
+        self.check(fsconfig_set_fd(self.fsfd.as_fd(), key, value.as_fd()))
             .with_context(|| format!("Failed to set {key}={value:?} on {:?}", self.name))?;
         Ok(self)
     }
@@ -60,28 +89,25 @@ impl FsHandle {
     }
 
     pub fn mount(&self) -> Result<MountHandle> {
-        fsconfig_create(self.fsfd.as_fd())?;
-
-        Ok(MountHandle::new(fsmount(
-            self.fsfd.as_fd(),
-            FsMountFlags::FSMOUNT_CLOEXEC,
-            MountAttrFlags::empty(),
-        )?))
+        self.check(fsconfig_create(self.fsfd.as_fd()))
+            .with_context(|| format!("Failed to create {:?} filesystem", self.name))?;
+
+        let mountfd = self
+            .check(fsmount(
+                self.fsfd.as_fd(),
+                FsMountFlags::FSMOUNT_CLOEXEC,
+                MountAttrFlags::empty(),
+            ))
+            .with_context(|| format!("Failed to mount {:?} filesystem", self.name))?;
+        Ok(MountHandle::new(mountfd))
     }
 }
 
+/// Anything that didn't come with an error (like warnings) gets printed when we're done.
 impl Drop for FsHandle {
     fn drop(&mut self) {
-        let mut buffer = [0u8; 1024];
-        loop {
-            match rustix::io::read(&self.fsfd, &mut buffer) {
-                Err(_) | Ok(0) => return, // ENODATA, among others?
-                Ok(size) => eprintln!(
-                    "{:?}: {}",
-                    self.name,
-                    String::from_utf8(buffer[0..size].to_vec()).unwrap()
-                ),
-            }
+        for message in self.read_messages() {
+            eprintln!("{:?}: {message}", self.name);
         }
     }
 }
@@ -121,6 +147,7 @@ impl MountHandle {
     pub fn make_readonly(&self) -> Result<()> {
         mount_setattr(
             &self.mountfd,
+            false,
             MountAttrFlags::MOUNT_ATTR_RDONLY,
             MountAttrFlags::empty(),
             MountPropagationFlags::empty(),
@@ -128,6 +155,18 @@ impl MountHandle {
         .context("Unable to make mount readonly")
     }
 
+    /// Like make_readonly(), but for submounts too.  Used on trees cloned from the host.
+    pub fn make_readonly_recursive(&self) -> Result<()> {
+        mount_setattr(
+            &self.mountfd,
+            true,
+            MountAttrFlags::MOUNT_ATTR_RDONLY,
+            MountAttrFlags::empty(),
+            MountPropagationFlags::empty(),
+        )
+        .context("Unable to make mounts readonly")
+    }
+
     pub fn move_to(&self, dirfd: impl AsFd, name: impl PathArg) -> Result<()> {
         move_mount(
             self.mountfd.as_fd(),
@@ -0,0 +1,35 @@
+use std::{mem::zeroed, os::fd::AsRawFd};
+
+use anyhow::{Context, Result};
+use rustix::{
+    net::{AddressFamily, SocketType, socket},
+    thread::{UnshareFlags, unshare},
+};
+
+/// Brings up the loopback interface in the current network namespace: it starts out down.
+fn loopback_up() -> Result<()> {
+    let sock = socket(AddressFamily::INET, SocketType::DGRAM, None)
+        .context("Unable to create socket to configure loopback")?;
+
+    // SAFETY: ifreq is plain old data, and we set the part of the union that we pass
+    let result = unsafe {
+        let mut req: libc::ifreq = zeroed();
+        for (dst, src) in req.ifr_name.iter_mut().zip(c"lo".to_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        req.ifr_ifru.ifru_flags = libc::IFF_UP as libc::c_short;
+        libc::ioctl(sock.as_raw_fd(), libc::SIOCSIFFLAGS, &req)
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()).context("Unable to bring up loopback interface"),
+    }
+}
+
+/// Moves the calling thread into a new network namespace, which only has a loopback interface.
+/// Threads and processes created afterwards inherit it.
+pub(super) fn unshare_network() -> Result<()> {
+    unshare(UnshareFlags::NEWNET).context("Unable to create new network namespace")?;
+    loopback_up()
+}
@@ -0,0 +1,196 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use libc::c_long;
+use rustix::io::Errno;
+
+use super::{
+    Device, MappingType, SandboxKind, ShareFlags,
+    cgroup::parse_size,
+    filesystem::Filesystem,
+    seccomp::parse_errno,
+    syscalls::{SyscallList, parse_syscall, parse_syscall_file},
+};
+
+/// Commandline options for tweaking the sandbox setup.
+#[derive(Args, Debug)]
+pub(crate) struct RunOptions {
+    #[clap(
+        long,
+        help = "Don't add an entry for root to /etc/passwd and /etc/group"
+    )]
+    pub(crate) no_etc_passwd_root_entry: bool,
+    #[clap(
+        long,
+        help = "Don't add an entry for host files to /etc/passwd and /etc/group"
+    )]
+    pub(crate) no_etc_passwd_host_entry: bool,
+    #[clap(
+        long,
+        alias = "as-root",
+        help = "Run as root inside the sandbox (shared files will appear to be owned by root)"
+    )]
+    pub(crate) map_current_user_as_root: bool,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t,
+        help = "How to map uids and gids into the sandbox"
+    )]
+    pub(crate) sandbox_type: SandboxKind,
+    #[clap(
+        long,
+        value_enum,
+        help = "Where your own uid and gid end up with newuidmap [default: user, or root with --as-root]"
+    )]
+    pub(crate) preserve: Option<MappingType>,
+    #[clap(long, help = "Override the zeroth argument passed to the command")]
+    pub(crate) argv0: Option<String>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Start the command in this directory of the sandbox (relative to home) [default: home]"
+    )]
+    pub(crate) working_directory: Option<PathBuf>,
+    #[clap(
+        long,
+        conflicts_with = "argv0",
+        help = "Print a summary of the syscalls made by the command when it exits (needs strace)"
+    )]
+    pub(crate) strace_summary: bool,
+    #[clap(
+        long,
+        help = "Pass the command a connection to the compositor in $WAYLAND_SOCKET, instead of a socket"
+    )]
+    pub(crate) wayland_socket: bool,
+    #[clap(
+        long,
+        value_enum,
+        help = "Share this with the sandbox, in addition to what the app asks for"
+    )]
+    pub(crate) share: Vec<ShareFlags>,
+    #[clap(
+        long,
+        value_enum,
+        alias = "unshare",
+        help = "Don't share this with the sandbox, even if the app asks for it"
+    )]
+    pub(crate) nosocket: Vec<ShareFlags>,
+    #[clap(long, help = "Don't restrict the syscalls the command can make")]
+    pub(crate) no_seccomp: bool,
+    #[clap(
+        long,
+        conflicts_with = "no_seccomp",
+        help = "Restrict the syscalls even if the config file turns that off"
+    )]
+    pub(crate) seccomp: bool,
+    #[clap(
+        long,
+        value_parser = parse_errno,
+        default_value = "EPERM",
+        conflicts_with = "no_seccomp",
+        help = "What syscalls denied by the seccomp filter return, like EPERM or ENOSYS"
+    )]
+    pub(crate) seccomp_return_errno: Errno,
+    #[clap(
+        long,
+        value_name = "SYSCALLS",
+        value_parser = parse_syscall,
+        value_delimiter = ',',
+        conflicts_with = "no_seccomp",
+        help = "Deny these syscalls too, like ptrace,personality"
+    )]
+    pub(crate) seccomp_deny: Vec<c_long>,
+    #[clap(
+        long,
+        value_name = "FILE",
+        value_parser = parse_syscall_file,
+        conflicts_with = "no_seccomp",
+        help = "Deny the syscalls listed in this file too, one per line (name or name=ERRNO)"
+    )]
+    pub(crate) seccomp_deny_file: Option<SyscallList>,
+    #[clap(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_env,
+        help = "Set an environment variable for the command, overriding the app and runtime"
+    )]
+    pub(crate) env: Vec<(String, String)>,
+    #[clap(
+        long,
+        value_name = "KEY",
+        help = "Unset an environment variable for the command"
+    )]
+    pub(crate) unset_env: Vec<String>,
+    #[clap(
+        long,
+        value_name = "DIR",
+        help = "Keep this directory in the home directory between runs, in ~/.var/app/ID"
+    )]
+    pub(crate) persist: Vec<String>,
+    #[clap(
+        long,
+        value_name = "PATH[:ro]",
+        help = "Mount this host directory (absolute or starting with ~/) in the sandbox"
+    )]
+    pub(crate) filesystem: Vec<Filesystem>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Make these host devices available, in addition to what the app asks for"
+    )]
+    pub(crate) device: Vec<Device>,
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "Limit the memory the command can use, like 512M or 2G"
+    )]
+    pub(crate) memory_max: Option<u64>,
+    #[clap(
+        long,
+        value_name = "WEIGHT",
+        value_parser = clap::value_parser!(u16).range(1..=10000),
+        help = "Set the relative CPU share of the command (1 to 10000, default 100)"
+    )]
+    pub(crate) cpu_weight: Option<u16>,
+    #[clap(
+        long,
+        value_name = "NAME",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "Give the sandbox its own hostname (by default, the ID of the app)"
+    )]
+    pub(crate) hostname: Option<Option<String>>,
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        default_value = "1G",
+        help = "Limit the size of each of /tmp, /dev/shm and the (unshared) home directory"
+    )]
+    pub(crate) tmpfs_size: u64,
+    #[clap(
+        long,
+        help = "Allow changes to /app for debugging (they're kept in memory, and lost on exit)"
+    )]
+    pub(crate) writable_app: bool,
+    #[clap(
+        long,
+        help = "Print the mounts and environment of the sandbox instead of running the command"
+    )]
+    pub(crate) dry_run: bool,
+    #[clap(
+        long,
+        help = "Kill the sandbox when this process gets killed, instead of leaving it running"
+    )]
+    pub(crate) die_with_parent: bool,
+}
+
+/// Parses a `KEY=VALUE` environment assignment.  The value may contain `=`, the key can't.
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, not {value}")),
+    }
+}
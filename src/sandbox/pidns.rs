@@ -0,0 +1,174 @@
+use std::{mem::ManuallyDrop, process::exit};
+
+use anyhow::{Context, Result, bail};
+use rustix::{
+    fd::{AsRawFd, OwnedFd},
+    io::{Errno, read, write},
+    pipe::{PipeFlags, pipe_with},
+    process::{
+        Pid, PidfdFlags, Resource, Rlimit, Signal, WaitOptions, WaitStatus, getpid, pidfd_open,
+        set_parent_process_death_signal, setrlimit, waitpid,
+    },
+    thread::{UnshareFlags, unshare},
+};
+
+use super::cgroup::Cgroup;
+
+/// Turns a wait status into an exit code, the way shells do: 128 plus the signal number if the
+/// process was killed.
+fn exit_code(status: WaitStatus) -> i32 {
+    match (status.exit_status(), status.terminating_signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 255,
+    }
+}
+
+/// Dies from the given signal, so that our caller sees the same thing as if it had run the
+/// command itself.  Exits with 128 plus the signal number if that doesn't work out.
+///
+/// This lowers RLIMIT_CORE to 0 first, on purpose.  For signals like SIGSEGV, dying would
+/// otherwise dump the core of this process, which has nothing to do with the crash: the command
+/// already dumped its own core (subject to the limits in the sandbox) when it crashed.
+pub(super) fn die_from_signal(signal: i32) -> ! {
+    let _ = setrlimit(
+        Resource::Core,
+        Rlimit {
+            current: Some(0),
+            maximum: None,
+        },
+    );
+    // SAFETY: We're about to go away, so there's no handler that we could break
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+    exit(128 + signal);
+}
+
+/// Checks if the process has exited.  Its pidfd becomes readable then.
+fn parent_exited(pidfd: &OwnedFd) -> Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: pidfd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: We pass a single valid pollfd, and don't wait
+    match unsafe { libc::poll(&mut pollfd, 1, 0) } {
+        -1 => Err(std::io::Error::last_os_error()).context("Unable to poll pidfd"),
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Kept by the init process to tell the original process how the command ended.  The init
+/// process can't die from a signal itself: the kernel ignores signals that the init process of a
+/// namespace sends itself.
+pub(super) struct ExitReporter(OwnedFd);
+
+impl ExitReporter {
+    /// Exits with the status of the command.  If it got killed by a signal, the original process
+    /// dies from the same signal, with RLIMIT_CORE lowered on purpose (see [`die_from_signal`]).
+    pub(super) fn exit(self, status: WaitStatus) -> ! {
+        if let Some(signal) = status.terminating_signal() {
+            let _ = write(&self.0, &[signal as u8]);
+        }
+        exit(exit_code(status));
+    }
+}
+
+/// Creates a new PID namespace and forks its init process, which is the only one to return from
+/// this function.  The original process stays behind to keep serving our FUSE filesystems: it
+/// waits for the init process and exits with its status.
+///
+/// We can't do this up front when unsharing the other namespaces: after unsharing the PID
+/// namespace, the calling thread can't create any more threads, and we need those for FUSE.
+///
+/// If there's a cgroup, the init process moves into it (taking everything it spawns along) and
+/// the original process removes it when it's done, by dropping it.  It also calls `on_exit` then, to clean up
+/// anything else on the host.  If the command got killed by a signal (reported through the
+/// ExitReporter), the original process kills itself with the same signal.
+///
+/// The FUSE threads can't wait until after the fork to be created (see above), but they wait for
+/// both processes to close `start_fuse` after it before they start serving.  This way, they're
+/// all blocked in read() when we fork, rather than holding a lock that the init process needs,
+/// like the one of the allocator or of stderr: the init process carries on with lots of work
+/// that allocates and logs before it execs the command.
+///
+/// With `die_with_parent`, the init process gets killed if the original process goes away, and
+/// the kernel then kills everything else in the namespace.  Otherwise, the sandbox keeps running
+/// without its FUSE filesystems.
+pub(super) fn enter_pid_namespace(
+    mut cgroup: Option<Cgroup>,
+    die_with_parent: bool,
+    start_fuse: OwnedFd,
+    on_exit: impl FnOnce(),
+) -> Result<ExitReporter> {
+    unshare(UnshareFlags::NEWPID).context("Unable to create new pid namespace")?;
+    let (reader, writer) = pipe_with(PipeFlags::CLOEXEC).context("Unable to create a pipe")?;
+    // We can't use getppid() in the init process to check if we're still around: it returns 0
+    let parent = pidfd_open(getpid(), PidfdFlags::empty()).context("Unable to open pidfd")?;
+
+    // SAFETY: The child only runs on this thread, so it mustn't depend on locks held by the FUSE
+    // threads.  They're all waiting for start_fuse to be closed, without holding any.
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("Unable to fork init process"),
+        0 => {
+            drop(start_fuse);
+            drop(reader);
+            // Only the original process removes the cgroup, even if we fail
+            let cgroup = cgroup.map(ManuallyDrop::new);
+            if die_with_parent {
+                set_parent_process_death_signal(Some(Signal::KILL))
+                    .context("Unable to set parent death signal")?;
+                if parent_exited(&parent)? {
+                    exit(1);
+                }
+            }
+            if let Some(cgroup) = cgroup {
+                cgroup.enter()?;
+            }
+            Ok(ExitReporter(writer))
+        }
+        pid => {
+            drop(start_fuse);
+            drop(writer);
+            // SAFETY: fork() returned a valid pid
+            let pid = unsafe { Pid::from_raw_unchecked(pid) };
+            loop {
+                match waitpid(Some(pid), WaitOptions::empty()) {
+                    Ok(Some((_, status))) => {
+                        // The kernel killed everything else in the namespace by now
+                        drop(cgroup.take());
+                        on_exit();
+
+                        let mut signal = [0u8];
+                        if let Ok(1) = read(&reader, &mut signal) {
+                            die_from_signal(signal[0].into());
+                        }
+                        if let Some(signal) = status.terminating_signal() {
+                            die_from_signal(signal);
+                        }
+                        exit(exit_code(status));
+                    }
+                    Ok(None) | Err(Errno::INTR) => continue,
+                    Err(err) => Err(err).context("Unable to wait for init process")?,
+                }
+            }
+        }
+    }
+}
+
+/// As the init process of the PID namespace, reaps all children until the given one exits and
+/// returns its status.  When we exit after that, the kernel kills whatever is left.
+pub(super) fn reap_until(pid: u32) -> Result<WaitStatus> {
+    loop {
+        match waitpid(None, WaitOptions::empty()) {
+            Ok(Some((reaped, status))) if reaped.as_raw_nonzero().get() as u32 == pid => {
+                return Ok(status);
+            }
+            Ok(_) | Err(Errno::INTR) => continue,
+            Err(Errno::CHILD) => bail!("Lost track of process {pid}"),
+            Err(err) => Err(err).context("Unable to wait for children")?,
+        }
+    }
+}
@@ -0,0 +1,488 @@
+// A seccomp filter along the lines of the one that upstream flatpak installs: a deny-list of
+// syscalls that apps have no business making, plus some argument filtering.
+
+use std::io::Write;
+
+use anyhow::{Context, Result, bail};
+use libc::{
+    BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W, SECCOMP_RET_ALLOW,
+    SECCOMP_RET_DATA, SECCOMP_RET_ERRNO, SECCOMP_SET_MODE_FILTER, c_long, sock_filter, sock_fprog,
+};
+use rustix::{io::Errno, thread::set_no_new_privs};
+
+use crate::manifest::Manifest;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc00000b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// A second ABI that the kernel runs, for 32-bit code.  Its syscalls have numbers of their own,
+/// which libc doesn't know about, so they're listed here next to the native ones.  Only the
+/// syscalls that the filter looks at are in the list.
+struct CompatAbi {
+    arch: u32,
+    syscalls: &'static [(c_long, u32)],
+}
+
+/// i386.  socketcall() gets allowed: the family of a socket it creates is behind a pointer, and
+/// 32-bit glibc uses it instead of socket() unless it was built for a recent kernel.
+#[cfg(target_arch = "x86_64")]
+const COMPAT_ABI: Option<CompatAbi> = Some(CompatAbi {
+    arch: 0x40000003,
+    syscalls: &[
+        (libc::SYS_add_key, 286),
+        (libc::SYS_keyctl, 288),
+        (libc::SYS_request_key, 287),
+        (libc::SYS_syslog, 103),
+        (libc::SYS_acct, 51),
+        (libc::SYS_quotactl, 131),
+        (libc::SYS_bpf, 357),
+        (libc::SYS_kexec_load, 283),
+        (libc::SYS_init_module, 128),
+        (libc::SYS_finit_module, 350),
+        (libc::SYS_delete_module, 129),
+        (libc::SYS_userfaultfd, 374),
+        (libc::SYS_move_pages, 317),
+        (libc::SYS_mbind, 274),
+        (libc::SYS_get_mempolicy, 275),
+        (libc::SYS_set_mempolicy, 276),
+        (libc::SYS_migrate_pages, 294),
+        (libc::SYS_unshare, 310),
+        (libc::SYS_setns, 346),
+        (libc::SYS_mount, 21),
+        // The old umount(), which only exists here
+        (libc::SYS_umount2, 22),
+        (libc::SYS_umount2, 52),
+        (libc::SYS_pivot_root, 217),
+        (libc::SYS_chroot, 61),
+        (libc::SYS_open_tree, 428),
+        (libc::SYS_move_mount, 429),
+        (libc::SYS_fsopen, 430),
+        (libc::SYS_fsconfig, 431),
+        (libc::SYS_fsmount, 432),
+        (libc::SYS_fspick, 433),
+        (libc::SYS_mount_setattr, 442),
+        (libc::SYS_clone3, 435),
+        (libc::SYS_perf_event_open, 336),
+        (libc::SYS_ptrace, 26),
+        (libc::SYS_socket, 359),
+        (libc::SYS_ioctl, 54),
+    ],
+});
+/// 32-bit ARM (EABI)
+#[cfg(target_arch = "aarch64")]
+const COMPAT_ABI: Option<CompatAbi> = Some(CompatAbi {
+    arch: 0x40000028,
+    syscalls: &[
+        (libc::SYS_add_key, 309),
+        (libc::SYS_keyctl, 311),
+        (libc::SYS_request_key, 310),
+        (libc::SYS_syslog, 103),
+        (libc::SYS_acct, 51),
+        (libc::SYS_quotactl, 131),
+        (libc::SYS_bpf, 386),
+        (libc::SYS_kexec_load, 347),
+        (libc::SYS_init_module, 128),
+        (libc::SYS_finit_module, 379),
+        (libc::SYS_delete_module, 129),
+        (libc::SYS_userfaultfd, 388),
+        (libc::SYS_move_pages, 344),
+        (libc::SYS_mbind, 319),
+        (libc::SYS_get_mempolicy, 320),
+        (libc::SYS_set_mempolicy, 321),
+        (libc::SYS_migrate_pages, 400),
+        (libc::SYS_unshare, 337),
+        (libc::SYS_setns, 375),
+        (libc::SYS_mount, 21),
+        (libc::SYS_umount2, 52),
+        (libc::SYS_pivot_root, 218),
+        (libc::SYS_chroot, 61),
+        (libc::SYS_open_tree, 428),
+        (libc::SYS_move_mount, 429),
+        (libc::SYS_fsopen, 430),
+        (libc::SYS_fsconfig, 431),
+        (libc::SYS_fsmount, 432),
+        (libc::SYS_fspick, 433),
+        (libc::SYS_mount_setattr, 442),
+        (libc::SYS_clone3, 435),
+        (libc::SYS_perf_event_open, 364),
+        (libc::SYS_ptrace, 26),
+        (libc::SYS_socket, 281),
+        (libc::SYS_ioctl, 54),
+    ],
+});
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const COMPAT_ABI: Option<CompatAbi> = None;
+
+// Offsets into struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+#[cfg(target_endian = "little")]
+const ARGS_OFFSET: u32 = 16;
+#[cfg(target_endian = "big")]
+const ARGS_OFFSET: u32 = 20; // the low half of the 64bit argument
+
+/// Syscalls that get denied.  If an errno is given, it's used instead of the configurable default:
+/// ENOSYS makes callers fall back to older syscalls instead of giving up.
+const DENIED: &[(c_long, Option<Errno>)] = &[
+    // Kernel keyring: not namespaced
+    (libc::SYS_add_key, None),
+    (libc::SYS_keyctl, None),
+    (libc::SYS_request_key, None),
+    // Kernel facilities that aren't for apps
+    (libc::SYS_syslog, None),
+    (libc::SYS_acct, None),
+    (libc::SYS_quotactl, None),
+    (libc::SYS_bpf, None),
+    (libc::SYS_kexec_load, None),
+    (libc::SYS_init_module, None),
+    (libc::SYS_finit_module, None),
+    (libc::SYS_delete_module, None),
+    // Making userspace page faults slow is a favourite trick for exploiting kernel races
+    (libc::SYS_userfaultfd, None),
+    // NUMA: can be abused to tweak the memory of other processes
+    (libc::SYS_move_pages, None),
+    (libc::SYS_mbind, None),
+    (libc::SYS_get_mempolicy, None),
+    (libc::SYS_set_mempolicy, None),
+    (libc::SYS_migrate_pages, None),
+    // No namespace or mount games inside of the sandbox
+    (libc::SYS_unshare, None),
+    (libc::SYS_setns, None),
+    (libc::SYS_mount, None),
+    (libc::SYS_umount2, None),
+    (libc::SYS_pivot_root, None),
+    (libc::SYS_chroot, None),
+    (libc::SYS_open_tree, Some(Errno::NOSYS)),
+    (libc::SYS_move_mount, Some(Errno::NOSYS)),
+    (libc::SYS_fsopen, Some(Errno::NOSYS)),
+    (libc::SYS_fsconfig, Some(Errno::NOSYS)),
+    (libc::SYS_fsmount, Some(Errno::NOSYS)),
+    (libc::SYS_fspick, Some(Errno::NOSYS)),
+    (libc::SYS_mount_setattr, Some(Errno::NOSYS)),
+    // We can't look at the flags of clone3() since they're behind a pointer, so make libc fall
+    // back to clone()
+    (libc::SYS_clone3, Some(Errno::NOSYS)),
+    (libc::SYS_perf_event_open, Some(Errno::NOSYS)),
+    // Debugging other processes, unless we're running a debugging tool
+    (libc::SYS_ptrace, None),
+];
+
+/// Socket families that apps may use.  Everything else fails with EAFNOSUPPORT.
+const ALLOWED_SOCKET_FAMILIES: &[i32] = &[
+    libc::AF_UNSPEC,
+    libc::AF_UNIX,
+    libc::AF_INET,
+    libc::AF_INET6,
+    libc::AF_NETLINK,
+];
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: usize, jf: usize) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: jt.try_into().expect("seccomp jump too long"),
+        jf: jf.try_into().expect("seccomp jump too long"),
+        k,
+    }
+}
+
+fn load(offset: u32) -> sock_filter {
+    stmt(BPF_LD | BPF_W | BPF_ABS, offset)
+}
+
+fn allow() -> sock_filter {
+    stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW)
+}
+
+fn deny(errno: Errno) -> sock_filter {
+    stmt(
+        BPF_RET | BPF_K,
+        SECCOMP_RET_ERRNO | (errno.raw_os_error() as u32 & SECCOMP_RET_DATA),
+    )
+}
+
+/// Builds the checks of the syscalls of one ABI, with `number` translating the native syscall
+/// numbers of libc into the ones of the ABI.  Syscalls that the ABI doesn't have are skipped.
+/// `denied` syscalls fail with their errno.  The program ends up allowing everything else.
+fn syscall_checks(
+    number: impl Fn(c_long) -> Vec<u32>,
+    denied: &[(c_long, Errno)],
+    x32: bool,
+) -> Vec<sock_filter> {
+    let mut filter = vec![load(NR_OFFSET)];
+
+    // x32 syscalls use the x86_64 arch, but with this bit set in the syscall number
+    if x32 {
+        filter.push(jump(BPF_JMP | BPF_JGE | BPF_K, 0x40000000, 0, 1));
+        filter.push(deny(Errno::NOSYS));
+    }
+
+    for &(nr, errno) in denied {
+        for nr in number(nr) {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr, 0, 1));
+            filter.push(deny(errno));
+        }
+    }
+
+    // socket(): check the family.  The jumps land on the allow at the end of the block.
+    let families = ALLOWED_SOCKET_FAMILIES.len();
+    for nr in number(libc::SYS_socket) {
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr, 0, families + 3));
+        filter.push(load(ARGS_OFFSET));
+        for (i, &family) in ALLOWED_SOCKET_FAMILIES.iter().enumerate() {
+            filter.push(jump(
+                BPF_JMP | BPF_JEQ | BPF_K,
+                family as u32,
+                families - i,
+                0,
+            ));
+        }
+        filter.push(deny(Errno::AFNOSUPPORT));
+        filter.push(allow());
+    }
+
+    // ioctl(TIOCSTI) pushes input into the terminal, which lets the app escape to the host shell
+    for nr in number(libc::SYS_ioctl) {
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr, 0, 4));
+        filter.push(load(ARGS_OFFSET + 8));
+        filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, libc::TIOCSTI as u32, 0, 1));
+        filter.push(deny(Errno::PERM));
+        filter.push(allow());
+    }
+
+    filter.push(allow());
+    filter
+}
+
+/// Builds the BPF program.  `errno` is what denied syscalls return, and `allow_ptrace` is for
+/// debugging tools like strace.  `extra` syscalls are denied on top of the default list, with
+/// their own errno if one is given.  They come first, so they can also change the errno of a
+/// syscall on the default list.
+///
+/// Syscalls of the compat ABI (like i386 on x86_64) get checks of their own.  The ones in `extra`
+/// are only denied there if they're in its list of syscalls.  Other ABIs are denied entirely.
+fn build_filter(
+    errno: Errno,
+    allow_ptrace: bool,
+    extra: &[(c_long, Option<Errno>)],
+) -> Result<Vec<sock_filter>> {
+    let Some(arch) = AUDIT_ARCH else {
+        bail!("No seccomp filter for this architecture: use --no-seccomp");
+    };
+
+    let denied: Vec<_> = extra
+        .iter()
+        .chain(
+            DENIED
+                .iter()
+                .filter(|(nr, _)| !(allow_ptrace && *nr == libc::SYS_ptrace)),
+        )
+        .map(|&(nr, override_errno)| (nr, override_errno.unwrap_or(errno)))
+        .collect();
+
+    let mut filter = vec![load(ARCH_OFFSET)];
+
+    if let Some(compat) = &COMPAT_ABI {
+        for (nr, _) in extra {
+            if !compat.syscalls.iter().any(|(native, _)| native == nr) {
+                log::debug!("Syscall {nr} only gets denied for the native ABI");
+            }
+        }
+
+        let number = |nr| {
+            compat
+                .syscalls
+                .iter()
+                .filter(|(native, _)| *native == nr)
+                .map(|(_, compat)| *compat)
+                .collect()
+        };
+        let checks = syscall_checks(number, &denied, false);
+        filter.push(jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            compat.arch,
+            0,
+            checks.len(),
+        ));
+        filter.extend(checks);
+    }
+
+    filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0));
+    filter.push(deny(Errno::NOSYS));
+    filter.extend(syscall_checks(
+        |nr| vec![nr as u32],
+        &denied,
+        cfg!(target_arch = "x86_64"),
+    ));
+
+    Ok(filter)
+}
+
+/// Installs the seccomp filter for the calling thread and everything it spawns afterwards.
+fn install_filter(
+    errno: Errno,
+    allow_ptrace: bool,
+    extra: &[(c_long, Option<Errno>)],
+) -> Result<()> {
+    let mut filter = build_filter(errno, allow_ptrace, extra)?;
+    let program = sock_fprog {
+        len: filter
+            .len()
+            .try_into()
+            .context("seccomp filter is too long")?,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Required to install a filter without CAP_SYS_ADMIN (which we dropped)
+    set_no_new_privs(true).context("Unable to set no_new_privs")?;
+
+    match unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            0,
+            &program as *const sock_fprog,
+        )
+    } {
+        0 => Ok(()),
+        -1 => Err(std::io::Error::last_os_error())
+            .context("Unable to install seccomp filter (use --no-seccomp to run without one)"),
+        _ => unreachable!(),
+    }
+}
+
+/// The settings of the filter of a sandbox.  They get recorded in the `[Seccomp]` section of the
+/// info of its instance, so that `enter` can install the same filter.
+#[derive(Debug, PartialEq)]
+pub(super) struct FilterInfo {
+    pub(super) errno: Errno,
+    pub(super) allow_ptrace: bool,
+    pub(super) extra: Vec<(c_long, Option<Errno>)>,
+}
+
+impl FilterInfo {
+    pub(super) fn write(&self, mut fp: impl Write) -> std::io::Result<()> {
+        writeln!(fp, "\n[Seccomp]")?;
+        writeln!(fp, "errno={}", self.errno.raw_os_error())?;
+        writeln!(fp, "allow-ptrace={}", self.allow_ptrace)?;
+        write!(fp, "deny=")?;
+        for (nr, errno) in &self.extra {
+            match errno {
+                Some(errno) => write!(fp, "{nr}:{};", errno.raw_os_error())?,
+                None => write!(fp, "{nr};")?,
+            }
+        }
+        writeln!(fp)
+    }
+
+    /// Reads the settings from the info of an instance, or None if it has no filter.
+    pub(super) fn read(info: &Manifest) -> Result<Option<Self>> {
+        let Some(errno) = info.get_opt("Seccomp", "errno") else {
+            return Ok(None);
+        };
+        let errno = parse_errno(errno).map_err(anyhow::Error::msg)?;
+        let allow_ptrace = info.get_opt("Seccomp", "allow-ptrace") == Some("true");
+
+        let mut extra = vec![];
+        for item in info.get_list("Seccomp", "deny") {
+            let (nr, errno) = match item.split_once(':') {
+                Some((nr, errno)) => (nr, Some(parse_errno(errno).map_err(anyhow::Error::msg)?)),
+                None => (item.as_str(), None),
+            };
+            let nr = nr
+                .parse()
+                .with_context(|| format!("Invalid syscall {nr}"))?;
+            extra.push((nr, errno));
+        }
+
+        Ok(Some(Self {
+            errno,
+            allow_ptrace,
+            extra,
+        }))
+    }
+
+    pub(super) fn install(&self) -> Result<()> {
+        install_filter(self.errno, self.allow_ptrace, &self.extra)
+    }
+}
+
+/// Parses an errno given by name (like `EPERM`) or number.
+pub(crate) fn parse_errno(value: &str) -> Result<Errno, String> {
+    let errno = match value.to_uppercase().as_str() {
+        "EPERM" => Errno::PERM,
+        "EACCES" => Errno::ACCESS,
+        "EINVAL" => Errno::INVAL,
+        "ENOSYS" => Errno::NOSYS,
+        "ENOTSUP" | "EOPNOTSUPP" => Errno::OPNOTSUPP,
+        other => match other.parse() {
+            Ok(raw @ 1..=4095) => Errno::from_raw_os_error(raw),
+            _ => {
+                return Err(format!(
+                    "Unknown errno {value}: use a name like EPERM or a number"
+                ));
+            }
+        },
+    };
+
+    Ok(errno)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn jumps_stay_inside() {
+        let extra = [
+            (libc::SYS_getpid, None),
+            (libc::SYS_ptrace, Some(Errno::NOSYS)),
+        ];
+        let filter = build_filter(Errno::PERM, false, &extra).unwrap();
+
+        for (i, insn) in filter.iter().enumerate() {
+            if u32::from(insn.code) & 0x07 == BPF_JMP {
+                assert!(i + 1 + usize::from(insn.jt.max(insn.jf)) < filter.len());
+            }
+        }
+        assert_eq!(u32::from(filter.last().unwrap().code), BPF_RET | BPF_K);
+
+        // Both ABIs get checked
+        let arches: Vec<_> = filter.iter().map(|insn| insn.k).collect();
+        assert!(arches.contains(&AUDIT_ARCH.unwrap()));
+        assert!(arches.contains(&COMPAT_ABI.unwrap().arch));
+    }
+
+    #[test]
+    fn filter_info_round_trips() {
+        let filter = FilterInfo {
+            errno: Errno::PERM,
+            allow_ptrace: true,
+            extra: vec![
+                (libc::SYS_getpid, None),
+                (libc::SYS_uname, Some(Errno::NOSYS)),
+            ],
+        };
+        let mut info = b"[Runtime]\nname=org.example.Platform\n".to_vec();
+        filter.write(&mut info).unwrap();
+
+        let manifest = Manifest::new(std::str::from_utf8(&info).unwrap()).unwrap();
+        assert_eq!(FilterInfo::read(&manifest).unwrap(), Some(filter));
+
+        let manifest = Manifest::new("[Runtime]\nname=org.example.Platform\n").unwrap();
+        assert_eq!(FilterInfo::read(&manifest).unwrap(), None);
+    }
+}
@@ -4,6 +4,7 @@ use rustix::{
     fs::{CWD, Mode, OFlags, open, openat},
     io::{Errno, write},
     path::Arg as PathArg,
+    thread::{CapabilityFlags, CapabilitySets, clear_ambient_capability_set, set_capabilities},
 };
 
 /// Writes the string to a given filename.  Really only suitable for stuff in /sys or /proc.
@@ -16,6 +17,36 @@ pub(super) fn write_to(filename: &str, content: &str) -> Result<()> {
     Ok(())
 }
 
+/// Gives up all capabilities for good.  Switching to a uid other than 0 does that by itself, but
+/// uid 0 keeps all of them in our user namespace, and gets them back on exec.  So the bounding set
+/// gets cleared first, which limits what exec can hand out, and then the sets of the thread.
+pub(super) fn drop_all_capabilities() -> Result<()> {
+    for cap in 0.. {
+        // SAFETY: PR_CAPBSET_DROP only takes a capability number
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) } != 0 {
+            match std::io::Error::last_os_error() {
+                // We went past the last capability that the kernel knows
+                err if err.raw_os_error() == Some(libc::EINVAL) && cap > 0 => break,
+                err => Err(err).context("Unable to clear the capability bounding set")?,
+            }
+        }
+    }
+
+    clear_ambient_capability_set().context("Unable to clear the ambient capabilities")?;
+    let empty = CapabilityFlags::empty();
+    set_capabilities(
+        None,
+        CapabilitySets {
+            effective: empty,
+            permitted: empty,
+            inheritable: empty,
+        },
+    )
+    .context("Unable to drop capabilities")?;
+
+    Ok(())
+}
+
 /// Opens a file with O_PATH plus the given flags.  Always sets CLOEXEC.
 pub(super) fn open_path(
     dirfd: impl AsFd,
@@ -56,3 +87,35 @@ pub(super) fn nameat(dirfd: impl AsFd, name: &str) -> String {
         format!("/proc/self/fd/{fd}/{name}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a line like `CapEff:\t000001ffffffffff` from the status of the current thread.
+    fn capabilities(name: &str) -> u64 {
+        let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+        let line = status
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{name}:")))
+            .unwrap();
+        u64::from_str_radix(line.trim(), 16).unwrap()
+    }
+
+    #[test]
+    fn root_keeps_no_capabilities() {
+        // Capabilities belong to threads, so this one takes them away from a thread of its own
+        std::thread::spawn(|| {
+            if capabilities("CapEff") == 0 {
+                eprintln!("Not running as root: nothing to drop");
+                return;
+            }
+            drop_all_capabilities().unwrap();
+            for name in ["CapInh", "CapPrm", "CapEff", "CapBnd", "CapAmb"] {
+                assert_eq!(capabilities(name), 0, "{name}");
+            }
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -1,12 +1,14 @@
 use std::{
     env,
+    ffi::OsString,
     os::unix::net::{UnixListener, UnixStream},
+    path::Path,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use rustix::{
-    fd::{AsFd, OwnedFd},
-    fs::OFlags,
+    fd::{AsFd, BorrowedFd, OwnedFd},
+    fs::{CWD, OFlags},
     pipe::{PipeFlags, pipe_with},
 };
 use wayland_client::{Connection, Dispatch, QueueHandle, protocol::wl_registry};
@@ -81,8 +83,15 @@ fn try_secure_listener(
     app_id: &str,
     instance_id: &str,
 ) -> Result<Option<OwnedFd>> {
-    let stream = UnixStream::connect(nameat(wayland_socket, ""))
-        .context("Unable to connect to host wayland socket")?;
+    // If we can't even connect (say, with ENAMETOOLONG because the path doesn't fit in sun_path)
+    // then we don't know about the extension either: the bind mount doesn't need to connect.
+    let stream = match UnixStream::connect(nameat(wayland_socket, "")) {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::debug!("Unable to connect to host wayland socket, falling back: {err}");
+            return Ok(None);
+        }
+    };
     let conn = Connection::from_socket(stream)?;
     let mut event_queue = conn.new_event_queue();
     let qhandle = event_queue.handle();
@@ -116,9 +125,43 @@ fn try_secure_listener(
     Ok(Some(close_fd_write))
 }
 
+/// Opens the wayland socket of the host (as an O_PATH fd), if there is a WAYLAND_DISPLAY.
+fn open_host_socket(hostdir: Option<BorrowedFd<'_>>) -> Result<Option<(OsString, OwnedFd)>> {
+    // No WAYLAND_DISPLAY?  Do nothing.
+    let Some(host_display) = env::var_os("WAYLAND_DISPLAY") else {
+        return Ok(None);
+    };
+
+    // WAYLAND_DISPLAY is evaluated relative to the XDG_RUNTIME_DIR but it can also be an absolute
+    // path.  This use of openat() will work in both cases (absolute or relative).
+    if hostdir.is_none() && !Path::new(&host_display).is_absolute() {
+        bail!("WAYLAND_DISPLAY {host_display:?} needs XDG_RUNTIME_DIR set on the host");
+    }
+    let socket = open_path(hostdir.unwrap_or(CWD), &host_display, OFlags::empty())
+        .with_context(|| format!("Cannot open host wayland socket {host_display:?}"))?;
+
+    Ok(Some((host_display, socket)))
+}
+
+/// Connects to the compositor of the host, for passing the connection to the sandbox in
+/// WAYLAND_SOCKET.  This works without any socket in the sandbox, but it also means that the
+/// wp_security_context_manager_v1 extension can't tell the compositor who's on the other end.
+///
+/// If there is no WAYLAND_DISPLAY set on the host, this returns None.
+pub(super) fn connect_wayland_socket(hostdir: Option<BorrowedFd<'_>>) -> Result<Option<OwnedFd>> {
+    let Some((host_display, socket)) = open_host_socket(hostdir)? else {
+        return Ok(None);
+    };
+
+    let stream = UnixStream::connect(nameat(&socket, ""))
+        .with_context(|| format!("Unable to connect to host wayland socket {host_display:?}"))?;
+    Ok(Some(stream.into()))
+}
+
 /// Binds the wayland socket inside of the sandbox.  This attempts to use the
-/// wp_security_context_manager_v1 extension to create a sandboxed listener, but if that fails, it
-/// will just fall back to bind mounting the socket from the host.
+/// wp_security_context_manager_v1 extension to create a sandboxed listener, but if the extension
+/// isn't there (or we can't connect to find out), it will just fall back to bind mounting the
+/// socket from the host.
 ///
 /// If there is no WAYLAND_DISPLAY set on the host, this returns None.  Otherwise, it returns the
 /// name of the WAYLAND_DISPLAY environment variable inside the sandbox plus an optional fd that
@@ -126,20 +169,14 @@ fn try_secure_listener(
 /// host then this function will bind a wayland socket in the sandbox, or return a failure.
 pub(super) fn bind_wayland_socket(
     runtime_dir: &DirBuilder,
-    hostdir: &OwnedFd,
+    hostdir: Option<BorrowedFd<'_>>,
     app_id: &str,
     instance_id: &str,
 ) -> Result<Option<(String, Option<OwnedFd>)>> {
-    // No WAYLAND_DISPLAY?  Do nothing.
-    let Some(host_display) = env::var_os("WAYLAND_DISPLAY") else {
+    let Some((_, socket)) = open_host_socket(hostdir)? else {
         return Ok(None);
     };
 
-    // WAYLAND_DISPLAY is evaluated relative to the XDG_RUNTIME_DIR but it can also be an absolute
-    // path.  This use of openat() will work in both cases (absolute or relative).
-    let socket = open_path(hostdir, &host_display, OFlags::empty())
-        .with_context(|| format!("Cannot open host wayland socket {host_display:?}"))?;
-
     // We always create our internal socket as "wayland-0"
     let sandbox_display = "wayland-0".to_string();
 
@@ -0,0 +1,70 @@
+use std::{env, path::PathBuf};
+
+use anyhow::{Context, Result};
+use rustix::{
+    fd::OwnedFd,
+    fs::{CWD, OFlags},
+};
+
+use super::{dirbuilder::DirBuilder, util::open_path};
+
+/// Parses the display number out of a local DISPLAY like `:0`, `:1.0` or `unix:0`.
+fn parse_display(display: &str) -> Option<u32> {
+    let (host, display) = display.rsplit_once(':')?;
+    if !host.is_empty() && host != "unix" {
+        return None;
+    }
+    let (number, _screen) = display.split_once('.').unwrap_or((display, ""));
+    number.parse().ok()
+}
+
+/// The X11 display of the host.  We open the socket and the Xauthority file up front: the host
+/// /tmp is gone by the time we populate the sandbox.
+pub(super) struct X11Display {
+    number: u32,
+    socket: OwnedFd,
+    xauthority: Option<(PathBuf, OwnedFd)>,
+}
+
+impl X11Display {
+    /// Finds the display from DISPLAY on the host, or returns None if there's no local display.
+    pub(super) fn from_env() -> Result<Option<Self>> {
+        let Some(display) = env::var_os("DISPLAY") else {
+            return Ok(None);
+        };
+        let Some(number) = display.to_str().and_then(parse_display) else {
+            log::debug!("Ignoring non-local X11 display {display:?}");
+            return Ok(None);
+        };
+
+        let path = format!("/tmp/.X11-unix/X{number}");
+        let socket = open_path(CWD, &path, OFlags::empty())
+            .with_context(|| format!("Cannot open host X11 socket {path:?}"))?;
+
+        let xauthority = env::var_os("XAUTHORITY")
+            .map(PathBuf::from)
+            .or_else(|| Some(dirs::home_dir()?.join(".Xauthority")))
+            .and_then(|path| {
+                let fd = open_path(CWD, &path, OFlags::empty()).ok()?;
+                Some((path, fd))
+            });
+
+        Ok(Some(Self {
+            number,
+            socket,
+            xauthority,
+        }))
+    }
+
+    /// Binds the socket into the /tmp of the sandbox and returns the DISPLAY to use there.
+    pub(super) fn bind_socket(&self, tmp: &DirBuilder) -> Result<String> {
+        let number = self.number;
+        tmp.bind_file(&format!(".X11-unix/X{number}"), &self.socket, "")?;
+        Ok(format!(":{number}"))
+    }
+
+    /// The Xauthority file on the host, if there is one.
+    pub(super) fn xauthority(&self) -> Option<&(PathBuf, OwnedFd)> {
+        self.xauthority.as_ref()
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result};
+use clap::{ArgMatches, parser::ValueSource};
+use serde::Deserialize;
+
+use crate::{Args, Cmd, sandbox::ShareFlags};
+
+/// Defaults from `~/.config/flatpak-next/config.toml`, like:
+///
+/// ```toml
+/// repository = "https://registry.example.com/"
+/// arch = "aarch64"
+/// share = ["wayland", "pulseaudio"]
+/// seccomp = false
+/// ```
+///
+/// Everything is optional, and the commandline wins over all of it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Settings {
+    repository: Option<String>,
+    arch: Option<String>,
+    /// Shared with every sandbox, in addition to what the app asks for
+    share: Vec<ShareFlags>,
+    /// Whether to restrict the syscalls in the sandbox
+    seccomp: Option<bool>,
+}
+
+impl Settings {
+    /// Reads the config file, or returns the built-in defaults if there is none.
+    pub(crate) fn load() -> Result<Self> {
+        let Some(path) = dirs::config_dir().map(|dir| dir.join("flatpak-next/config.toml")) else {
+            return Ok(Self::default());
+        };
+
+        config::Config::builder()
+            .add_source(config::File::from(path.as_path()).required(false))
+            .build()
+            .and_then(config::Config::try_deserialize)
+            .with_context(|| format!("Invalid config file {path:?}"))
+    }
+
+    /// Fills in the defaults for whatever wasn't given on the commandline.  For running apps,
+    /// the shared things get added to --share (so --nosocket still wins over them), and asking
+    /// for seccomp on the commandline (--seccomp, or any of the options that tune the filter) wins
+    /// over turning it off.
+    pub(crate) fn apply(&self, matches: &ArgMatches, args: &mut Args) {
+        let from_commandline =
+            |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let (Some(repository), false) = (&self.repository, from_commandline("repository")) {
+            args.repository.clone_from(repository);
+        }
+        if let (Some(arch), false) = (&self.arch, from_commandline("arch")) {
+            args.arch.clone_from(arch);
+        }
+
+        if let Cmd::Run { options, .. } = &mut args.command {
+            options.share.extend(&self.share);
+            let run_from_commandline = |id: &str| {
+                matches
+                    .subcommand_matches("run")
+                    .is_some_and(|run| run.value_source(id) == Some(ValueSource::CommandLine))
+            };
+            let wants_seccomp = options.seccomp
+                || !options.seccomp_deny.is_empty()
+                || options.seccomp_deny_file.is_some()
+                || run_from_commandline("seccomp_return_errno");
+            if self.seccomp == Some(false) && !wants_seccomp {
+                options.no_seccomp = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    fn no_seccomp(settings: &Settings, commandline: &[&str]) -> bool {
+        let matches = Args::command().get_matches_from(commandline);
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        settings.apply(&matches, &mut args);
+        let Cmd::Run { options, .. } = args.command else {
+            panic!("not a run command");
+        };
+        options.no_seccomp
+    }
+
+    #[test]
+    fn seccomp_off_in_config() {
+        let settings = Settings {
+            seccomp: Some(false),
+            ..Default::default()
+        };
+        let app = "app/org.example.App/x86_64/stable";
+
+        assert!(no_seccomp(&settings, &["flatpak-next", "run", app]));
+        assert!(!no_seccomp(
+            &settings,
+            &["flatpak-next", "run", "--seccomp", app]
+        ));
+        assert!(!no_seccomp(
+            &settings,
+            &["flatpak-next", "run", "--seccomp-deny=ptrace", app]
+        ));
+        assert!(!no_seccomp(
+            &settings,
+            &["flatpak-next", "run", "--seccomp-return-errno=ENOSYS", app]
+        ));
+        assert!(!no_seccomp(
+            &Settings::default(),
+            &["flatpak-next", "run", app]
+        ));
+    }
+}